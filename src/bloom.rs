@@ -0,0 +1,143 @@
+use crate::compress::{CompressedSeq, Kmer};
+use std::{f64::consts::LN_2, marker::PhantomData};
+
+/// Bits in one block, which is one 64-byte cache line.
+const BLOCK_BITS: usize = 512;
+
+/// Words in one block.
+const BLOCK_WORDS: usize = BLOCK_BITS / 64;
+
+/// A blocked Bloom filter of k-mers. All of the bits for one k-mer are
+/// set within a single cache line, so checking a k-mer that was never
+/// added costs at most one cache miss, and usually none when the
+/// filter is small enough to stay in cache.
+#[derive(Debug, Clone)]
+pub struct BloomFilter<K: Kmer = CompressedSeq> {
+    words: Vec<u64>,
+    num_blocks: usize,
+    hash_count: u32,
+    kmer: PhantomData<K>,
+}
+
+impl<K: Kmer> BloomFilter<K> {
+    /// Sizes a filter to hold `items_count` k-mers with a false positive
+    /// rate of about `fp_prob`.
+    pub fn new(items_count: usize, fp_prob: f64) -> BloomFilter<K> {
+        let items = items_count.max(1) as f64;
+        let size = (-items * fp_prob.ln() / (LN_2 * LN_2)).ceil() as usize;
+        let num_blocks = size.div_ceil(BLOCK_BITS).max(1);
+        let bits_per_item = (num_blocks * BLOCK_BITS) as f64 / items;
+        let hash_count = (bits_per_item * LN_2).round().clamp(1.0, 16.0);
+
+        BloomFilter {
+            words: vec![0; num_blocks * BLOCK_WORDS],
+            num_blocks,
+            hash_count: hash_count as u32,
+            kmer: PhantomData,
+        }
+    }
+
+    /// The words of the block for `item` and the bits to set in it,
+    /// one for each hash function.
+    #[inline]
+    fn bits(&self, item: K) -> (usize, impl Iterator<Item = usize>) {
+        let mixed = item.mix();
+        let block =
+            ((mixed as u128 * self.num_blocks as u128) >> 64) as usize;
+
+        // The block is picked by the high bits, so draw the bits within
+        // it from rescrambled copies to keep the two independent. Each
+        // copy is good for seven 9-bit positions.
+        let bits = (0..self.hash_count as usize).scan(mixed, |state, i| {
+            if i % 7 == 0 {
+                *state = state.wrapping_add(0x9e3779b97f4a7c15).mix();
+            }
+            Some((*state >> (9 * (i % 7))) as usize % BLOCK_BITS)
+        });
+
+        (block * BLOCK_WORDS, bits)
+    }
+
+    /// Add an item in the filter
+    pub fn add(&mut self, item: K) {
+        let (start, bits) = self.bits(item);
+        for bit in bits {
+            self.words[start + bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Check for existence of an item in filter. A `false` is
+    /// certain, a `true` only probable.
+    #[inline]
+    pub fn check(&self, item: K) -> bool {
+        let (start, mut bits) = self.bits(item);
+        bits.all(|bit| self.words[start + bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bloom::BloomFilter;
+    use crate::compress::WideCompressedSeq;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn bloom_create() {
+        // 1% takes about 9.6 bits and 7 hashes an item
+        let filter: BloomFilter = BloomFilter::new(1000, 0.01);
+        assert_eq!(filter.num_blocks, 19);
+        assert_eq!(filter.words.len(), 19 * 8);
+        assert_eq!(filter.hash_count, 7);
+        assert!(filter.words.iter().all(|word| *word == 0));
+
+        let filter: BloomFilter = BloomFilter::new(0, 0.01);
+        assert_eq!(filter.num_blocks, 1);
+        assert!(filter.hash_count >= 1);
+    }
+
+    #[test]
+    fn bloom_no_false_negatives() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let items: Vec<u64> = (0..10_000).map(|_| rng.gen()).collect();
+        let mut filter: BloomFilter = BloomFilter::new(items.len(), 0.01);
+        for item in &items {
+            filter.add(*item);
+        }
+
+        // Poly-A included
+        filter.add(0);
+        assert!(filter.check(0));
+        assert!(items.iter().all(|item| filter.check(*item)));
+    }
+
+    #[test]
+    fn bloom_false_positive_rate() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for fp_prob in [0.1, 0.01, 0.001] {
+            let mut filter: BloomFilter = BloomFilter::new(10_000, fp_prob);
+            for _ in 0..10_000 {
+                filter.add(rng.gen());
+            }
+
+            // Blocking costs a little accuracy, so allow some slack
+            let trials = 100_000;
+            let passed =
+                (0..trials).filter(|_| filter.check(rng.gen())).count();
+            let rate = passed as f64 / trials as f64;
+            assert!(rate < fp_prob * 2.0, "{rate} for {fp_prob}");
+        }
+    }
+
+    #[test]
+    fn bloom_wide_keys() {
+        let wide = u128::MAX - 5;
+        let mut filter: BloomFilter<WideCompressedSeq> =
+            BloomFilter::new(2, 0.001);
+        filter.add(wide);
+        filter.add(11);
+
+        assert!(filter.check(wide));
+        assert!(filter.check(11));
+        assert!(!filter.check(wide ^ (1 << 64)));
+    }
+}
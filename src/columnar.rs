@@ -0,0 +1,245 @@
+use crate::{
+    counter::Tally,
+    output::{counts, FIELDS},
+};
+use arrow::{
+    array::{ArrayRef, AsArray, StringBuilder, UInt64Builder},
+    datatypes::{DataType, Field, Schema, SchemaRef, UInt64Type},
+    ipc::{reader::FileReader, writer::FileWriter},
+    record_batch::RecordBatch,
+};
+use parquet::arrow::{
+    arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter,
+};
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+};
+
+/// The columnar formats count tables can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Columnar {
+    Parquet,
+    /// The Arrow IPC file format
+    Arrow,
+}
+
+/// The schema of every columnar count table: the fields of the text
+/// tables, with the sample, junction and sequence as strings and
+/// everything else as unsigned 64-bit counts. None of them are null.
+pub fn schema() -> SchemaRef {
+    let fields: Vec<Field> = FIELDS
+        .iter()
+        .enumerate()
+        .map(|(index, name)| {
+            let data_type = if index < 3 {
+                DataType::Utf8
+            } else {
+                DataType::UInt64
+            };
+            Field::new(*name, data_type, false)
+        })
+        .collect();
+    Arc::new(Schema::new(fields))
+}
+
+/// The rows of a count table, gathered a column at a time.
+#[derive(Default)]
+pub struct CountTable {
+    strings: [StringBuilder; 3],
+    counts: [UInt64Builder; 7],
+}
+
+impl CountTable {
+    /// Adds the hits on one junction in one sample.
+    pub fn add(
+        &mut self,
+        sample: &str,
+        junction: &str,
+        seq: &str,
+        tally: Tally,
+        reads: u64,
+    ) {
+        for (column, value) in
+            self.strings.iter_mut().zip([sample, junction, seq])
+        {
+            column.append_value(value);
+        }
+        for (column, value) in
+            self.counts.iter_mut().zip(counts(tally, reads))
+        {
+            column.append_value(value);
+        }
+    }
+
+    /// The rows added so far, in one batch.
+    pub fn finish(mut self) -> RecordBatch {
+        let strings = self
+            .strings
+            .iter_mut()
+            .map(|column| Arc::new(column.finish()) as ArrayRef);
+        let counts = self
+            .counts
+            .iter_mut()
+            .map(|column| Arc::new(column.finish()) as ArrayRef);
+        RecordBatch::try_new(schema(), strings.chain(counts).collect())
+            .expect("every column follows the schema")
+    }
+
+    /// Writes the table to `out`.
+    pub fn write(
+        self,
+        out: impl Write + Send,
+        format: Columnar,
+    ) -> io::Result<()> {
+        let batch = self.finish();
+        match format {
+            Columnar::Parquet => {
+                let mut writer = ArrowWriter::try_new(out, schema(), None)?;
+                writer.write(&batch)?;
+                writer.close()?;
+            }
+            Columnar::Arrow => {
+                let mut writer = FileWriter::try_new(out, &schema())
+                    .map_err(io::Error::other)?;
+                writer.write(&batch).map_err(io::Error::other)?;
+                writer.finish().map_err(io::Error::other)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads back a table written by `CountTable::write`.
+pub fn read_table(
+    path: &Path,
+    format: Columnar,
+) -> io::Result<Vec<RecordBatch>> {
+    let file = File::open(path)?;
+    let batches: Result<Vec<_>, _> = match format {
+        Columnar::Parquet => ParquetRecordBatchReaderBuilder::try_new(file)?
+            .build()?
+            .collect(),
+        Columnar::Arrow => FileReader::try_new(file, None)
+            .map_err(io::Error::other)?
+            .collect(),
+    };
+    batches.map_err(io::Error::other)
+}
+
+/// The number of rows of tables read back with any hits, exact or
+/// within the mismatches.
+pub fn count_hits(batches: &[RecordBatch]) -> usize {
+    batches
+        .iter()
+        .map(|batch| {
+            // The counts before the reads
+            let hits: Vec<_> = (3..FIELDS.len() - 1)
+                .map(|column| {
+                    batch.column(column).as_primitive::<UInt64Type>()
+                })
+                .collect();
+            (0..batch.num_rows())
+                .filter(|row| hits.iter().any(|hits| hits.value(*row) > 0))
+                .count()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        columnar::{count_hits, read_table, schema, Columnar, CountTable},
+        counter::Tally,
+    };
+    use arrow::array::{AsArray, RecordBatch};
+    use arrow::datatypes::UInt64Type;
+    use tempfile::NamedTempFile;
+
+    fn strings(batch: &RecordBatch, column: usize) -> Vec<&str> {
+        batch
+            .column(column)
+            .as_string::<i32>()
+            .iter()
+            .flatten()
+            .collect()
+    }
+
+    fn counts(batch: &RecordBatch, column: usize) -> Vec<u64> {
+        batch
+            .column(column)
+            .as_primitive::<UInt64Type>()
+            .values()
+            .to_vec()
+    }
+
+    #[test]
+    fn columnar_round_trip() {
+        for format in [Columnar::Parquet, Columnar::Arrow] {
+            let mut table = CountTable::default();
+            table.add(
+                "a.fa",
+                "one",
+                "ACGT",
+                Tally {
+                    exact: (3, 1),
+                    approx: (2, 0),
+                },
+                10,
+            );
+            table.add(
+                "a.fa",
+                "two, \"quoted\"",
+                "TTTT",
+                Tally::default(),
+                10,
+            );
+            table.add(
+                "b.fa",
+                "one",
+                "ACGT",
+                Tally {
+                    exact: (u64::MAX, 0),
+                    approx: (0, 0),
+                },
+                u64::MAX,
+            );
+
+            let file = NamedTempFile::new().unwrap();
+            table.write(file.reopen().unwrap(), format).unwrap();
+            let batches = read_table(file.path(), format).unwrap();
+            assert_eq!(batches.len(), 1, "{format:?}");
+
+            let batch = &batches[0];
+            assert_eq!(batch.schema(), schema(), "{format:?}");
+            assert_eq!(strings(batch, 0), ["a.fa", "a.fa", "b.fa"]);
+            assert_eq!(strings(batch, 1), ["one", "two, \"quoted\"", "one"]);
+            assert_eq!(strings(batch, 2), ["ACGT", "TTTT", "ACGT"]);
+            assert_eq!(counts(batch, 3), [3, 0, u64::MAX]);
+            assert_eq!(counts(batch, 4), [1, 0, 0]);
+            assert_eq!(counts(batch, 5), [4, 0, u64::MAX]);
+            assert_eq!(counts(batch, 6), [2, 0, 0]);
+            assert_eq!(counts(batch, 8), [2, 0, 0]);
+            assert_eq!(counts(batch, 9), [10, 10, u64::MAX]);
+            assert_eq!(count_hits(&batches), 2, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn columnar_empty() {
+        for format in [Columnar::Parquet, Columnar::Arrow] {
+            let file = NamedTempFile::new().unwrap();
+            CountTable::default()
+                .write(file.reopen().unwrap(), format)
+                .unwrap();
+            let rows: usize = read_table(file.path(), format)
+                .unwrap()
+                .iter()
+                .map(|batch| batch.num_rows())
+                .sum();
+            assert_eq!(rows, 0, "{format:?}");
+        }
+    }
+}
@@ -1,6 +1,108 @@
+use crate::error::Rejection;
+use std::{
+    fmt::Debug,
+    ops::{BitAnd, BitOr, BitXor, Shl, Shr},
+};
+
 pub type CompressedSeq = u64;
 
-const ALPHABET_ENCODINGS: [u64; 256] = [
+/// A compressed sequence for k-mers too long to fit in a `CompressedSeq`.
+pub type WideCompressedSeq = u128;
+
+/// The longest k-mer that can be compressed at all.
+pub const MAX_KMER_SIZE: usize = WideCompressedSeq::MAX_KMER_SIZE;
+
+/// An unsigned integer that holds a 2-bit-per-base compressed k-mer.
+/// Everything downstream of `compress_seq` is generic over this so the
+/// narrowest type that fits the k-mer size can be chosen at runtime.
+pub trait Kmer:
+    Copy
+    + Debug
+    + Default
+    + Eq
+    + Ord
+    + std::hash::Hash
+    + Send
+    + Sync
+    + From<u8>
+    + BitAnd<Output = Self>
+    + BitOr<Output = Self>
+    + BitXor<Output = Self>
+    + Shl<usize, Output = Self>
+    + Shr<usize, Output = Self>
+{
+    /// The longest k-mer that will fit in this type.
+    const MAX_KMER_SIZE: usize;
+
+    /// All bits set.
+    const MAX: Self;
+
+    /// The bytes of the k-mer, as they are stored in an index file.
+    type Bytes: AsRef<[u8]> + AsMut<[u8]> + Default;
+
+    /// Scrambles the k-mer so that every bit of the result depends on
+    /// every base, not just the last few.
+    fn mix(self) -> u64;
+
+    /// The k-mer as little-endian bytes.
+    fn to_bytes(self) -> Self::Bytes;
+
+    /// The k-mer written by `to_bytes`.
+    fn from_bytes(bytes: Self::Bytes) -> Self;
+}
+
+impl Kmer for CompressedSeq {
+    const MAX_KMER_SIZE: usize = 32;
+    const MAX: Self = Self::MAX;
+    type Bytes = [u8; 8];
+
+    #[inline]
+    fn mix(self) -> u64 {
+        fmix64(self)
+    }
+
+    fn to_bytes(self) -> [u8; 8] {
+        self.to_le_bytes()
+    }
+
+    fn from_bytes(bytes: [u8; 8]) -> Self {
+        Self::from_le_bytes(bytes)
+    }
+}
+
+impl Kmer for WideCompressedSeq {
+    const MAX_KMER_SIZE: usize = 64;
+    const MAX: Self = Self::MAX;
+    type Bytes = [u8; 16];
+
+    #[inline]
+    fn mix(self) -> u64 {
+        fmix64(self as u64 ^ fmix64((self >> 64) as u64))
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        self.to_le_bytes()
+    }
+
+    fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self::from_le_bytes(bytes)
+    }
+}
+
+/// The MurmurHash3 64-bit finalizer.
+#[inline]
+fn fmix64(mut x: u64) -> u64 {
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51afd7ed558ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ceb9fe1a85ec53);
+    x ^ (x >> 33)
+}
+
+/// The encoding of any character that is not a base.
+pub const INVALID_BASE: u8 = 255;
+
+const ALPHABET_ENCODINGS: [u8; 256] = [
     255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
     255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
     255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
@@ -21,70 +123,466 @@ const ALPHABET_ENCODINGS: [u64; 256] = [
     255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
 ];
 
+/// `ALPHABET_ENCODINGS` with uracil read as thymine.
+const URACIL_ENCODINGS: [u8; 256] = {
+    let mut encodings = ALPHABET_ENCODINGS;
+    encodings[b'U' as usize] = 3;
+    encodings[b'u' as usize] = 3;
+    encodings
+};
+
+/// The set of characters accepted as bases, and how each is encoded.
+/// Every possible byte has an encoding, so any input can be encoded
+/// without bounds checks failing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Alphabet {
+    encodings: &'static [u8; 256],
+}
+
+impl Alphabet {
+    /// Creates an alphabet of A, C, G and T, also reading U as T
+    /// when `uracil` is set.
+    pub fn new(uracil: bool) -> Alphabet {
+        Alphabet {
+            encodings: if uracil {
+                &URACIL_ENCODINGS
+            } else {
+                &ALPHABET_ENCODINGS
+            },
+        }
+    }
+
+    #[inline]
+    pub fn encode(self, c: u8) -> u8 {
+        self.encodings[c as usize]
+    }
+
+    /// Returns the encodings of every base `c` could stand for,
+    /// which is more than one for an IUPAC ambiguity code and none
+    /// for a character that isn't a base at all.
+    pub fn bases(self, c: u8) -> &'static [u8] {
+        match self.encode(c) {
+            0 => &[0],
+            1 => &[1],
+            2 => &[2],
+            3 => &[3],
+            _ => match c.to_ascii_uppercase() {
+                b'R' => &[0, 2],
+                b'Y' => &[1, 3],
+                b'S' => &[1, 2],
+                b'W' => &[0, 3],
+                b'K' => &[2, 3],
+                b'M' => &[0, 1],
+                b'B' => &[1, 2, 3],
+                b'D' => &[0, 2, 3],
+                b'H' => &[0, 1, 3],
+                b'V' => &[0, 1, 2],
+                b'N' => &[0, 1, 2, 3],
+                _ => &[],
+            },
+        }
+    }
+
+    /// Creates and returns a compressed version of the given `Seq`,
+    /// provided that the instance is exactly `k` base pairs long and
+    /// contains nothing but bases.
+    pub fn compress<K: Kmer>(self, seq: &str, k: usize) -> Option<K> {
+        if seq.len() != k || k > K::MAX_KMER_SIZE {
+            return None;
+        }
+
+        seq.bytes().try_fold(K::default(), |seq, chr| {
+            match self.encode(chr) {
+                INVALID_BASE => None,
+                mask => Some((seq << 2) | K::from(mask)),
+            }
+        })
+    }
+
+    /// Compresses every concrete k-mer the given `Seq` could stand for,
+    /// expanding IUPAC ambiguity codes, as long as there are no more
+    /// than `max_expansions` of them.
+    pub fn expand<K: Kmer>(
+        self,
+        seq: &str,
+        k: usize,
+        max_expansions: usize,
+    ) -> Result<Vec<K>, Rejection> {
+        if seq.len() != k || k > K::MAX_KMER_SIZE {
+            return Err(Rejection::Length {
+                len: seq.len(),
+                kmer_size: k,
+            });
+        }
+
+        let mut kmers = vec![K::default()];
+        for chr in seq.bytes() {
+            let bases = self.bases(chr);
+            if bases.is_empty() {
+                return Err(Rejection::InvalidBase(char::from(chr)));
+            }
+
+            if kmers.len() * bases.len() > max_expansions {
+                return Err(Rejection::TooManyExpansions(max_expansions));
+            }
+
+            kmers = kmers
+                .into_iter()
+                .flat_map(|kmer| {
+                    bases.iter().map(move |base| (kmer << 2) | K::from(*base))
+                })
+                .collect();
+        }
+
+        Ok(kmers)
+    }
+}
+
+impl Default for Alphabet {
+    fn default() -> Self {
+        Alphabet::new(true)
+    }
+}
+
 #[inline]
-pub fn encode_char(c: char) -> u64 {
-    ALPHABET_ENCODINGS[c as usize]
+pub fn encode_char(c: u8) -> u8 {
+    Alphabet::default().encode(c)
 }
 
-/// Creates and returns a compressed version of the given `Seq`,
-/// provided that the instance is exactly 32 base pairs long.
-pub fn compress_seq(seq: &str) -> Option<CompressedSeq> {
-    (seq.len() == 32).then(|| {
-        seq.chars()
-            .fold(0, |seq, chr| (seq << 2) | encode_char(chr))
-    })
+/// Returns a mask covering the low `2 * k` bits of a `Kmer`,
+/// which is exactly the space used by an encoded k-mer.
+pub fn kmer_mask<K: Kmer>(k: usize) -> K {
+    if k >= K::MAX_KMER_SIZE {
+        K::MAX
+    } else {
+        // Build the mask from the top down so we never shift by the
+        // full width of the type.
+        (0..k).fold(K::default(), |mask, _| (mask << 2) | K::from(3))
+    }
+}
+
+/// Returns the reverse complement of a compressed k-mer. Since the
+/// encoding puts complementary bases at `x` and `3 - x`, complementing
+/// a base is just flipping both of its bits.
+pub fn reverse_complement<K: Kmer>(kmer: K, k: usize) -> K {
+    let base = K::from(3);
+    (0..k)
+        .fold((kmer, K::default()), |(fwd, rc), _| {
+            (fwd >> 2, (rc << 2) | ((fwd & base) ^ base))
+        })
+        .1
+}
+
+/// Returns the lesser of a compressed k-mer and its reverse complement,
+/// so that a k-mer and its reverse complement share one representation.
+pub fn canonical<K: Kmer>(kmer: K, k: usize) -> K {
+    kmer.min(reverse_complement(kmer, k))
+}
+
+/// Returns every k-mer within `max_distance` substitutions of a
+/// compressed k-mer, not including the k-mer itself. Substitutions are
+/// made at strictly increasing positions so each neighbor appears once.
+pub fn neighbors<K: Kmer>(kmer: K, k: usize, max_distance: usize) -> Vec<K> {
+    fn substitute<K: Kmer>(
+        kmer: K,
+        first: usize,
+        k: usize,
+        max_distance: usize,
+        found: &mut Vec<K>,
+    ) {
+        for pos in first..k {
+            for flip in 1..=3 {
+                let neighbor = kmer ^ (K::from(flip) << (2 * pos));
+                found.push(neighbor);
+                if max_distance > 1 {
+                    substitute(neighbor, pos + 1, k, max_distance - 1, found);
+                }
+            }
+        }
+    }
+
+    let mut found = vec![];
+    if max_distance > 0 {
+        substitute(kmer, 0, k, max_distance, &mut found);
+    }
+    found
+}
+
+/// Compresses `seq` with the default `Alphabet`.
+pub fn compress_seq<K: Kmer>(seq: &str, k: usize) -> Option<K> {
+    Alphabet::default().compress(seq, k)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{compress_seq, encode_char};
+    use super::{
+        canonical, compress_seq, encode_char, kmer_mask, neighbors,
+        reverse_complement, Alphabet, CompressedSeq, WideCompressedSeq,
+    };
+    use std::collections::HashSet;
 
     #[test]
     fn test_encode_char() {
-        for (index, character) in ['A', 'C', 'G', 'T'].iter().enumerate() {
-            assert_eq!(encode_char(*character), index as u64);
+        for (index, character) in b"ACGT".iter().enumerate() {
+            assert_eq!(encode_char(*character), index as u8);
         }
 
-        for (index, character) in ['a', 'c', 'g', 't'].iter().enumerate() {
-            assert_eq!(encode_char(*character), index as u64);
+        for (index, character) in b"acgt".iter().enumerate() {
+            assert_eq!(encode_char(*character), index as u8);
         }
     }
 
+    #[test]
+    fn test_encode_uracil() {
+        assert_eq!(encode_char(b'U'), 3);
+        assert_eq!(encode_char(b'u'), 3);
+
+        let alphabet = Alphabet::new(true);
+        assert_eq!(alphabet.encode(b'U'), 3);
+        assert_eq!(alphabet.encode(b'u'), 3);
+        assert_eq!(alphabet.encode(b'T'), 3);
+
+        let alphabet = Alphabet::new(false);
+        assert_eq!(alphabet.encode(b'U'), 255);
+        assert_eq!(alphabet.encode(b'u'), 255);
+        assert_eq!(alphabet.encode(b'T'), 3);
+    }
+
     #[test]
     fn test_encode_invalid_char() {
-        for character in &['N', 'F', 'K', 'z', 'y'] {
+        for character in b"NFKzy" {
             assert_eq!(encode_char(*character), 255);
         }
+
+        // Every byte has an encoding, including those of multibyte
+        // characters and the ones above the ASCII range
+        for character in "€\u{ff}".bytes().chain(128..=255) {
+            assert_eq!(encode_char(character), 255);
+        }
     }
 
     #[test]
     fn test_compress_seq() {
-        let res = compress_seq("A");
+        let res = compress_seq::<CompressedSeq>("A", 32);
         assert!(res.is_none());
 
-        let res = compress_seq(&"A".repeat(32));
+        let res = compress_seq::<CompressedSeq>(&"A".repeat(32), 32);
         assert_eq!(res, Some(0));
 
-        let res = compress_seq(&"a".repeat(32));
+        let res = compress_seq::<CompressedSeq>(&"a".repeat(32), 32);
         assert_eq!(res, Some(0));
 
-        let res = compress_seq(&"C".repeat(32));
+        let res = compress_seq::<CompressedSeq>(&"C".repeat(32), 32);
         assert_eq!(res, Some(0x5555555555555555));
 
-        let res = compress_seq(&"G".repeat(32));
+        let res = compress_seq::<CompressedSeq>(&"G".repeat(32), 32);
         assert_eq!(res, Some(0xaaaaaaaaaaaaaaaa));
 
-        let res = compress_seq(&"T".repeat(32));
+        let res = compress_seq::<CompressedSeq>(&"T".repeat(32), 32);
         println!("{:#x}", res.unwrap());
         assert_eq!(res, Some(0xffffffffffffffff));
 
-        let res = compress_seq(&"N".repeat(32));
-        assert_eq!(res, Some(0xffffffffffffffff));
+        let res = compress_seq::<CompressedSeq>(&"N".repeat(32), 32);
+        assert!(res.is_none());
+
+        let res = compress_seq::<CompressedSeq>(&"ACGTN".repeat(8)[..32], 32);
+        assert!(res.is_none());
 
-        let res = compress_seq(&"ACGT".repeat(8));
+        let res = compress_seq::<CompressedSeq>(&"ACGT".repeat(8), 32);
         assert_eq!(res, Some(0x1b1b1b1b1b1b1b1b));
 
-        let res = compress_seq(&"TGCA".repeat(8));
+        let res = compress_seq::<CompressedSeq>(&"TGCA".repeat(8), 32);
         assert_eq!(res, Some(0xe4e4e4e4e4e4e4e4));
     }
+
+    #[test]
+    fn test_kmer_mask() {
+        assert_eq!(kmer_mask::<CompressedSeq>(1), 0x3);
+        assert_eq!(kmer_mask::<CompressedSeq>(21), 0x3ffffffffff);
+        assert_eq!(kmer_mask::<CompressedSeq>(31), 0x3fffffffffffffff);
+        assert_eq!(kmer_mask::<CompressedSeq>(32), 0xffffffffffffffff);
+        assert_eq!(kmer_mask::<WideCompressedSeq>(33), 0x3ffffffffffffffff);
+        assert_eq!(kmer_mask::<WideCompressedSeq>(64), u128::MAX);
+    }
+
+    #[test]
+    fn test_compress_seq_kmer_size() {
+        let res = compress_seq::<CompressedSeq>(&"A".repeat(21), 25);
+        assert!(res.is_none());
+
+        let res = compress_seq::<CompressedSeq>(&"A".repeat(33), 33);
+        assert!(res.is_none());
+
+        let res = compress_seq::<CompressedSeq>("T", 1);
+        assert_eq!(res, Some(0x3));
+
+        let res = compress_seq::<CompressedSeq>(&"T".repeat(21), 21);
+        assert_eq!(res, Some(0x3ffffffffff));
+
+        let res = compress_seq::<CompressedSeq>(&"ACGT".repeat(6), 24);
+        assert_eq!(res, Some(0x1b1b1b1b1b1b));
+
+        let res = compress_seq::<CompressedSeq>(&"G".repeat(25), 25);
+        assert_eq!(res, Some(0x2aaaaaaaaaaaa));
+
+        let res = compress_seq::<CompressedSeq>(&"C".repeat(27), 27);
+        assert_eq!(res, Some(0x15555555555555));
+    }
+
+    #[test]
+    fn test_compress_wide_seq() {
+        let res = compress_seq::<CompressedSeq>(&"A".repeat(40), 40);
+        assert!(res.is_none());
+
+        let res = compress_seq::<WideCompressedSeq>(&"A".repeat(65), 65);
+        assert!(res.is_none());
+
+        let res = compress_seq::<WideCompressedSeq>(&"T".repeat(64), 64);
+        assert_eq!(res, Some(u128::MAX));
+
+        let res = compress_seq::<WideCompressedSeq>(&"ACGT".repeat(10), 40);
+        assert_eq!(res, Some(0x1b1b1b1b1b1b1b1b1b1b));
+
+        // The narrow and wide encodings agree wherever both fit.
+        let seq = "GATTACA".repeat(4);
+        let narrow = compress_seq::<CompressedSeq>(&seq, 28).unwrap();
+        let wide = compress_seq::<WideCompressedSeq>(&seq, 28).unwrap();
+        assert_eq!(narrow as u128, wide);
+
+        let seq = format!("{}{}", "T".repeat(32), "A".repeat(31));
+        let res = compress_seq::<WideCompressedSeq>(&seq, 63);
+        assert_eq!(res, Some((u64::MAX as u128) << 62));
+    }
+
+    #[test]
+    fn test_reverse_complement() {
+        let pairs = [
+            ("A", "T"),
+            ("ACGTT", "AACGT"),
+            ("GATTACAGATTACAGATTACA", "TGTAATCTGTAATCTGTAATC"),
+            (&"ACGT".repeat(8), &"ACGT".repeat(8)),
+        ];
+        for (seq, rc) in pairs {
+            let k = seq.len();
+            let fwd = compress_seq::<CompressedSeq>(seq, k).unwrap();
+            let rev = compress_seq::<CompressedSeq>(rc, k).unwrap();
+            assert_eq!(reverse_complement(fwd, k), rev);
+            assert_eq!(reverse_complement(rev, k), fwd);
+            assert_eq!(canonical(fwd, k), fwd.min(rev));
+            assert_eq!(canonical(rev, k), fwd.min(rev));
+        }
+
+        let seq = format!("{}{}", "C".repeat(40), "A".repeat(23));
+        let rc = format!("{}{}", "T".repeat(23), "G".repeat(40));
+        let fwd = compress_seq::<WideCompressedSeq>(&seq, 63).unwrap();
+        let rev = compress_seq::<WideCompressedSeq>(&rc, 63).unwrap();
+        assert_eq!(reverse_complement(fwd, 63), rev);
+        assert_eq!(canonical(rev, 63), fwd);
+    }
+
+    #[test]
+    fn test_compress_uracil() {
+        let dna = compress_seq::<CompressedSeq>(&"ACGT".repeat(8), 32);
+        let rna = compress_seq::<CompressedSeq>(&"ACGU".repeat(8), 32);
+        assert_eq!(rna, dna);
+
+        let rna = compress_seq::<CompressedSeq>(&"acgu".repeat(8), 32);
+        assert_eq!(rna, dna);
+
+        let alphabet = Alphabet::new(true);
+        let res =
+            alphabet.compress::<CompressedSeq>(&"GAUUACA".repeat(3), 21);
+        let dna =
+            alphabet.compress::<CompressedSeq>(&"GATTACA".repeat(3), 21);
+        assert_eq!(res, dna);
+        assert!(res.is_some());
+    }
+
+    #[test]
+    fn test_expand() {
+        let alphabet = Alphabet::default();
+        let res = alphabet.expand::<CompressedSeq>(&"ACGT".repeat(8), 32, 1);
+        assert_eq!(res.unwrap(), [0x1b1b1b1b1b1b1b1b]);
+
+        let res = alphabet.expand::<CompressedSeq>("ARY", 3, 4);
+        assert_eq!(res.unwrap(), [0b000001, 0b000011, 0b001001, 0b001011]);
+
+        let res = alphabet.expand::<CompressedSeq>("nn", 2, 16);
+        assert_eq!(res.unwrap(), (0..16).collect::<Vec<_>>());
+
+        let res =
+            alphabet.expand::<WideCompressedSeq>(&"B".repeat(40), 40, 1);
+        assert_eq!(res.unwrap_err().to_string(), "more than 1 expansions");
+
+        let res = alphabet.expand::<CompressedSeq>("ANY", 3, 7);
+        assert_eq!(res.unwrap_err().to_string(), "more than 7 expansions");
+
+        let res = alphabet.expand::<CompressedSeq>("ACFT", 4, 16);
+        assert_eq!(res.unwrap_err().to_string(), r#"invalid base "F""#);
+
+        let res = alphabet.expand::<CompressedSeq>("ACGT", 5, 16);
+        assert_eq!(res.unwrap_err().to_string(), "length 4 is not 5");
+
+        // Every expansion is a concrete k-mer the code stands for
+        for (code, expected) in [
+            ("R", "AG"),
+            ("Y", "CT"),
+            ("S", "CG"),
+            ("W", "AT"),
+            ("K", "GT"),
+            ("M", "AC"),
+            ("B", "CGT"),
+            ("D", "AGT"),
+            ("H", "ACT"),
+            ("V", "ACG"),
+            ("N", "ACGT"),
+        ] {
+            let res = alphabet.expand::<CompressedSeq>(code, 1, 4).unwrap();
+            let concrete: Vec<CompressedSeq> = expected
+                .chars()
+                .map(|base| compress_seq(&base.to_string(), 1).unwrap())
+                .collect();
+            assert_eq!(res, concrete, "{code}");
+        }
+    }
+
+    #[test]
+    fn test_neighbors() {
+        let hamming = |a: CompressedSeq, b: CompressedSeq| {
+            (0..32)
+                .filter(|pos| (a >> (2 * pos)) & 3 != (b >> (2 * pos)) & 3)
+                .count()
+        };
+
+        let kmer = compress_seq::<CompressedSeq>("GATTACA", 7).unwrap();
+        assert!(neighbors(kmer, 7, 0).is_empty());
+
+        // 3 substitutions at each of 7 positions, plus 9 for each of the
+        // 21 pairs of positions
+        for (distance, expected) in [(1, 21), (2, 21 + 189)] {
+            let found = neighbors(kmer, 7, distance);
+            assert_eq!(found.len(), expected);
+
+            let unique: HashSet<_> = found.iter().collect();
+            assert_eq!(unique.len(), expected);
+
+            for neighbor in found {
+                assert!(neighbor < 1 << 14);
+                assert!((1..=distance).contains(&hamming(kmer, neighbor)));
+            }
+        }
+
+        let acgt = compress_seq::<CompressedSeq>("ACGT", 4).unwrap();
+        let found = neighbors(acgt, 4, 1);
+        for seq in ["TCGT", "AAGT", "ACGG", "ACTT"] {
+            let neighbor = compress_seq(seq, 4).unwrap();
+            assert!(found.contains(&neighbor), "{seq}");
+        }
+
+        let wide = compress_seq::<WideCompressedSeq>(&"C".repeat(40), 40);
+        let found = neighbors(wide.unwrap(), 40, 1);
+        let seq = format!("G{}", "C".repeat(39));
+        assert!(found.contains(&compress_seq(&seq, 40).unwrap()));
+        assert_eq!(found.len(), 120);
+    }
 }
@@ -4,3 +4,6 @@ pub const HASH_CAPACITY_MULTIPLE: usize = 8;
 
 /// Size for all the buffers we use for reading FASTA files.
 pub const BUFFER_SIZE: usize = 4096;
+
+/// Reads handed to a search thread at a time.
+pub const READ_BATCH_SIZE: usize = 1024;
@@ -0,0 +1,990 @@
+use crate::{
+    compress::{
+        Alphabet, CompressedSeq, Kmer, WideCompressedSeq, MAX_KMER_SIZE,
+    },
+    constants::READ_BATCH_SIZE,
+    counts::{AtomicCounts, Count, Counts, Hits},
+    error::{Error, Rejection, Result},
+    hash::{Hash, Table},
+    index::{
+        index_header, save_index, Backend, Credit, IndexHeader,
+        IndexSettings, SavedIndex, SavedTable,
+    },
+    panel::{get_reader, Ambiguous, Junction, Panel, PanelOptions},
+    perfect::PerfectHash,
+    search::{JunctionIndex, Search, Strand},
+};
+use rayon::prelude::*;
+use std::{
+    collections::HashMap, fmt, iter, marker::PhantomData, mem, panic,
+    sync::mpsc, thread,
+};
+
+/// Forward and reverse complement hits on one junction, for k-mers
+/// that match it exactly and for those within the allowed mismatches.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tally {
+    pub exact: (u64, u64),
+    pub approx: (u64, u64),
+}
+
+/// What became of the reads searched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub reads: u64,
+    pub short_reads: u64,
+    pub lookups: u64,
+    pub rejected: u64,
+}
+
+impl Stats {
+    /// The stats of two shares of the same reads taken together.
+    pub fn merge(self, other: Stats) -> Stats {
+        Stats {
+            reads: self.reads + other.reads,
+            short_reads: self.short_reads + other.short_reads,
+            lookups: self.lookups + other.lookups,
+            rejected: self.rejected + other.rejected,
+        }
+    }
+}
+
+/// The hits on every junction of a `Counter` from one set of reads.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tallies {
+    /// The hits on each junction, in the order the junctions were given
+    pub tallies: Vec<Tally>,
+    pub stats: Stats,
+}
+
+/// Sets up a `Counter`: how the junctions are read and looked up, and
+/// how the hits on them are counted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CounterBuilder {
+    kmer_size: Option<usize>,
+    strand: Strand,
+    ambiguous: Ambiguous,
+    max_expansions: usize,
+    max_mismatches: u8,
+    uracil: bool,
+    backend: Backend,
+    wide_counts: bool,
+    prefilter: Option<f64>,
+    shared_counts: bool,
+}
+
+impl Default for CounterBuilder {
+    fn default() -> CounterBuilder {
+        CounterBuilder {
+            kmer_size: None,
+            strand: Strand::default(),
+            ambiguous: Ambiguous::default(),
+            max_expansions: 256,
+            max_mismatches: 0,
+            uracil: true,
+            backend: Backend::default(),
+            wide_counts: false,
+            prefilter: None,
+            shared_counts: false,
+        }
+    }
+}
+
+impl CounterBuilder {
+    /// Length of the junction k-mers, leaving out junctions of any
+    /// other length. By default each junction is matched at its own
+    /// length, whatever the lengths of the others.
+    pub fn kmer_size(mut self, kmer_size: usize) -> CounterBuilder {
+        self.kmer_size = Some(kmer_size);
+        self
+    }
+
+    /// Strand(s) of the reads to search.
+    pub fn strand(mut self, strand: Strand) -> CounterBuilder {
+        self.strand = strand;
+        self
+    }
+
+    /// What to do with junctions containing IUPAC ambiguity codes.
+    pub fn ambiguous(mut self, ambiguous: Ambiguous) -> CounterBuilder {
+        self.ambiguous = ambiguous;
+        self
+    }
+
+    /// Most k-mers an ambiguous junction may expand to.
+    pub fn max_expansions(mut self, max_expansions: usize) -> CounterBuilder {
+        self.max_expansions = max_expansions;
+        self
+    }
+
+    /// Also count hits within this many mismatches, tallied apart.
+    pub fn max_mismatches(mut self, max_mismatches: u8) -> CounterBuilder {
+        self.max_mismatches = max_mismatches;
+        self
+    }
+
+    /// Whether U is read as T, which it is by default, or as an
+    /// invalid base.
+    pub fn uracil(mut self, uracil: bool) -> CounterBuilder {
+        self.uracil = uracil;
+        self
+    }
+
+    /// How the junction k-mers are looked up.
+    pub fn backend(mut self, backend: Backend) -> CounterBuilder {
+        self.backend = backend;
+        self
+    }
+
+    /// Count up to 2^64 - 1 hits per k-mer instead of 2^32 - 1.
+    pub fn wide_counts(mut self, wide_counts: bool) -> CounterBuilder {
+        self.wide_counts = wide_counts;
+        self
+    }
+
+    /// Check each k-mer against a Bloom filter sized for a false
+    /// positive rate of `fp_rate` before looking it up.
+    pub fn prefilter(mut self, fp_rate: f64) -> CounterBuilder {
+        self.prefilter = Some(fp_rate);
+        self
+    }
+
+    /// Have all threads searching a file count into one table of atomic
+    /// counters rather than each keeping its own.
+    pub fn shared_counts(mut self, shared_counts: bool) -> CounterBuilder {
+        self.shared_counts = shared_counts;
+        self
+    }
+
+    /// A counter of the junctions in a FASTA or FASTQ file.
+    pub fn junctions_file(self, filename: &str) -> Result<Counter> {
+        let panel = Panel::read(filename, self.panel_options()?)?;
+        self.with_narrowest(panel)
+    }
+
+    /// A counter of the given junctions, each a name and a sequence.
+    pub fn junctions<N, S>(
+        self,
+        junctions: impl IntoIterator<Item = (N, S)>,
+    ) -> Result<Counter>
+    where
+        N: AsRef<str>,
+        S: AsRef<str>,
+    {
+        let mut panel = Panel::new(self.panel_options()?);
+        for (name, seq) in junctions {
+            panel.add(name.as_ref(), seq.as_ref());
+        }
+        self.with_narrowest(panel)
+    }
+
+    /// A counter of the junctions in an index written by
+    /// `Counter::save`. The index settles the k-mer size, strand,
+    /// mismatches and backend, whatever the builder was given.
+    pub fn index_file(mut self, filename: &str) -> Result<Counter> {
+        let IndexHeader { key_size, settings } = index_header(filename)?;
+        self.kmer_size = None;
+        self.strand = settings.strand;
+        self.max_mismatches = settings.max_mismatches;
+        self.backend = settings.backend;
+
+        if key_size <= CompressedSeq::MAX_KMER_SIZE {
+            self.load::<CompressedSeq>(filename)
+        } else {
+            self.load::<WideCompressedSeq>(filename)
+        }
+    }
+
+    fn panel_options(&self) -> Result<PanelOptions> {
+        if let Some(kmer_size) = self.kmer_size {
+            if !(1..=MAX_KMER_SIZE).contains(&kmer_size) {
+                return Err(Error::KmerSize(kmer_size));
+            }
+        }
+
+        Ok(PanelOptions {
+            alphabet: Alphabet::new(self.uracil),
+            kmer_size: self.kmer_size,
+            strand: self.strand,
+            ambiguous: self.ambiguous,
+            max_expansions: self.max_expansions,
+            max_mismatches: self.max_mismatches,
+        })
+    }
+
+    fn settings(&self, kmer_sizes: Vec<usize>) -> IndexSettings {
+        IndexSettings {
+            kmer_sizes,
+            strand: self.strand,
+            max_mismatches: self.max_mismatches,
+            backend: self.backend,
+        }
+    }
+
+    /// Counts with the narrowest compressed sequence that will hold the
+    /// k-mers of every junction accepted into `panel`.
+    fn with_narrowest(
+        self,
+        panel: Panel<WideCompressedSeq>,
+    ) -> Result<Counter> {
+        if panel
+            .longest()
+            .is_some_and(|k| k <= CompressedSeq::MAX_KMER_SIZE)
+        {
+            self.with_panel::<CompressedSeq>(panel.narrow())
+        } else {
+            self.with_panel::<WideCompressedSeq>(panel)
+        }
+    }
+
+    fn with_panel<K: Kmer + 'static>(
+        self,
+        panel: Panel<K>,
+    ) -> Result<Counter> {
+        match (self.backend, self.wide_counts) {
+            (Backend::Hash, false) => self.build::<K, Hash<K>, u32>(panel),
+            (Backend::Hash, true) => self.build::<K, Hash<K>, u64>(panel),
+            (Backend::Perfect, false) => {
+                self.build::<K, PerfectHash<K>, u32>(panel)
+            }
+            (Backend::Perfect, true) => {
+                self.build::<K, PerfectHash<K>, u64>(panel)
+            }
+        }
+    }
+
+    fn load<K: Kmer + 'static>(self, filename: &str) -> Result<Counter> {
+        match (self.backend, self.wide_counts) {
+            (Backend::Hash, false) => {
+                self.reload::<K, Hash<K>, u32>(SavedIndex::load(filename)?)
+            }
+            (Backend::Hash, true) => {
+                self.reload::<K, Hash<K>, u64>(SavedIndex::load(filename)?)
+            }
+            (Backend::Perfect, false) => self
+                .reload::<K, PerfectHash<K>, u32>(SavedIndex::load(
+                    filename,
+                )?),
+            (Backend::Perfect, true) => self
+                .reload::<K, PerfectHash<K>, u64>(SavedIndex::load(
+                    filename,
+                )?),
+        }
+    }
+
+    fn build<K: Kmer + 'static, T: Table<K> + 'static, C: Count + 'static>(
+        self,
+        panel: Panel<K>,
+    ) -> Result<Counter> {
+        if panel.groups.is_empty() {
+            return Err(Error::NoJunctions);
+        }
+
+        let parts = panel
+            .groups
+            .into_iter()
+            .map(|group| {
+                Ok(Part {
+                    index: JunctionIndex::new(
+                        &group.junctions,
+                        group.kmer_size,
+                        self.strand,
+                    )?,
+                    credits: group.credits,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let mut counter =
+            self.finish::<K, T, C>(parts, panel.names, panel.seqs);
+        counter.rejected = panel.rejected;
+        counter.duplicated = panel.duplicated;
+        Ok(counter)
+    }
+
+    fn reload<
+        K: Kmer + 'static,
+        T: Table<K> + 'static,
+        C: Count + 'static,
+    >(
+        self,
+        saved: SavedIndex<K, T>,
+    ) -> Result<Counter> {
+        let strand = saved.settings.strand;
+        let parts = saved
+            .tables
+            .into_iter()
+            .zip(saved.settings.kmer_sizes)
+            .map(|(SavedTable { table, credits }, kmer_size)| Part {
+                index: JunctionIndex::from_table(table, kmer_size, strand),
+                credits,
+            })
+            .collect();
+        Ok(self.finish::<K, T, C>(parts, saved.names, saved.seqs))
+    }
+
+    fn finish<
+        K: Kmer + 'static,
+        T: Table<K> + 'static,
+        C: Count + 'static,
+    >(
+        self,
+        parts: Vec<Part<K, T>>,
+        names: Vec<String>,
+        seqs: Vec<String>,
+    ) -> Counter {
+        let kmer_sizes =
+            parts.iter().map(|part| part.index.kmer_size()).collect();
+        let parts = parts
+            .into_iter()
+            .map(|part| Part {
+                index: match self.prefilter {
+                    Some(fp_rate) => part.index.with_prefilter(fp_rate),
+                    _ => part.index,
+                },
+                ..part
+            })
+            .collect();
+
+        let engine: Indexed<K, T, C> = Indexed {
+            parts,
+            alphabet: Alphabet::new(self.uracil),
+            junctions: names.len(),
+            counts: PhantomData,
+        };
+
+        Counter {
+            engine: Box::new(engine),
+            names,
+            seqs,
+            settings: self.settings(kmer_sizes),
+            rejected: vec![],
+            duplicated: vec![],
+            shared_counts: self.shared_counts,
+        }
+    }
+}
+
+/// Counts the reads that hit each of a panel of junctions.
+///
+/// ```no_run
+/// use tallyman::Counter;
+///
+/// let counter = Counter::builder()
+///     .kmer_size(21)
+///     .junctions_file("junctions.fa")?;
+/// let tallies = counter.count_file("reads.fq")?;
+/// for (name, tally) in counter.names().iter().zip(&tallies.tallies) {
+///     println!("{name}\t{}", tally.exact.0);
+/// }
+/// # Ok::<(), tallyman::Error>(())
+/// ```
+pub struct Counter {
+    engine: Box<dyn Engine>,
+    names: Vec<String>,
+    seqs: Vec<String>,
+    settings: IndexSettings,
+    rejected: Vec<(Junction, Rejection)>,
+    duplicated: Vec<Junction>,
+    shared_counts: bool,
+}
+
+impl fmt::Debug for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Counter")
+            .field("names", &self.names)
+            .field("settings", &self.settings)
+            .field("kmers", &self.kmers())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Counter {
+    pub fn builder() -> CounterBuilder {
+        CounterBuilder::default()
+    }
+
+    /// The names of the junctions, in the order they were given.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The sequences of the junctions, as they were given.
+    pub fn seqs(&self) -> &[String] {
+        &self.seqs
+    }
+
+    /// The k-mer sizes, strand, mismatches and backend counted with.
+    pub fn settings(&self) -> &IndexSettings {
+        &self.settings
+    }
+
+    /// The number of distinct k-mers looked up.
+    pub fn kmers(&self) -> usize {
+        self.engine.kmers()
+    }
+
+    /// Junctions left out, and why. An index keeps none of these.
+    pub fn rejected(&self) -> &[(Junction, Rejection)] {
+        &self.rejected
+    }
+
+    /// Junctions sharing a k-mer with one given before them, which
+    /// keeps the hits on it.
+    pub fn duplicated(&self) -> &[Junction] {
+        &self.duplicated
+    }
+
+    /// Starts counting reads fed in one at a time.
+    pub fn feed(&self) -> Feed<'_> {
+        Feed(self.engine.feeder())
+    }
+
+    /// Counts the hits in a number of reads.
+    pub fn count_seqs<S: AsRef<[u8]>>(
+        &self,
+        seqs: impl IntoIterator<Item = S>,
+    ) -> Tallies {
+        let mut feed = self.feed();
+        for seq in seqs {
+            feed.feed(seq.as_ref());
+        }
+        feed.finish()
+    }
+
+    /// Counts the hits in a FASTA or FASTQ file on all of the threads.
+    /// The totals are just what a single thread would have found.
+    pub fn count_file(&self, filename: &str) -> Result<Tallies> {
+        self.engine.count_file(filename, self.shared_counts)
+    }
+
+    /// Writes an index of the junctions to `filename`, so they can be
+    /// counted against without being read again.
+    pub fn save(&self, filename: &str) -> Result<()> {
+        self.engine
+            .save(filename, &self.settings, &self.names, &self.seqs)
+    }
+}
+
+/// Reads being counted one at a time by a `Counter`.
+pub struct Feed<'a>(Box<dyn Feeder + 'a>);
+
+impl Feed<'_> {
+    /// Counts the hits in one read.
+    pub fn feed(&mut self, seq: &[u8]) {
+        self.0.feed(seq);
+    }
+
+    /// The hits on every junction in all of the reads fed.
+    pub fn finish(self) -> Tallies {
+        self.0.finish()
+    }
+}
+
+/// A panel indexed with one kind of k-mer, table and count, so that a
+/// `Counter` can pick them when the junctions are read.
+trait Engine: Send + Sync {
+    fn kmers(&self) -> usize;
+    fn feeder(&self) -> Box<dyn Feeder + '_>;
+    fn count_file(
+        &self,
+        filename: &str,
+        shared_counts: bool,
+    ) -> Result<Tallies>;
+    fn save(
+        &self,
+        filename: &str,
+        settings: &IndexSettings,
+        names: &[String],
+        seqs: &[String],
+    ) -> Result<()>;
+}
+
+trait Feeder {
+    fn feed(&mut self, seq: &[u8]);
+    fn finish(self: Box<Self>) -> Tallies;
+}
+
+/// The index of the junction k-mers of one size, and the junctions
+/// credited with the hits on each of them.
+struct Part<K: Kmer, T: Table<K>> {
+    index: JunctionIndex<K, T>,
+    credits: HashMap<K, Vec<Credit>>,
+}
+
+struct Indexed<K: Kmer, T: Table<K>, C: Count> {
+    /// A part for each k-mer size, shortest first
+    parts: Vec<Part<K, T>>,
+    alphabet: Alphabet,
+    junctions: usize,
+    counts: PhantomData<C>,
+}
+
+impl<K: Kmer, T: Table<K>, C: Count> Indexed<K, T, C> {
+    /// Sums the hits on each junction's k-mers, given a search of
+    /// each part.
+    fn tally<H: Hits>(
+        &self,
+        searches: &[Search<K, T, H>],
+        stats: Stats,
+    ) -> Tallies {
+        let mut tallies = vec![Tally::default(); self.junctions];
+        let entries =
+            self.parts.iter().zip(searches).flat_map(|(part, search)| {
+                search.entries().map(move |(key, count, rc_count)| {
+                    (part.credits.get(&key), count, rc_count)
+                })
+            });
+        for (credits, count, rc_count) in entries {
+            let Some(credits) = credits else {
+                continue;
+            };
+
+            for credit in credits {
+                let (forward, reverse) = if credit.flipped {
+                    (rc_count, count)
+                } else {
+                    (count, rc_count)
+                };
+
+                let tally = &mut tallies[credit.junction];
+                let hits = if credit.exact {
+                    &mut tally.exact
+                } else {
+                    &mut tally.approx
+                };
+                hits.0 += forward;
+                hits.1 += reverse;
+            }
+        }
+        Tallies { tallies, stats }
+    }
+}
+
+impl<K: Kmer, T: Table<K>, C: Count> Engine for Indexed<K, T, C> {
+    fn kmers(&self) -> usize {
+        self.parts
+            .iter()
+            .map(|part| part.index.table.entries().count())
+            .sum()
+    }
+
+    fn feeder(&self) -> Box<dyn Feeder + '_> {
+        Box::new(Fed {
+            indexed: self,
+            searches: self
+                .parts
+                .iter()
+                .map(|part| Search::new(&part.index, self.alphabet))
+                .collect(),
+            stats: Stats::default(),
+        })
+    }
+
+    fn count_file(
+        &self,
+        filename: &str,
+        shared_counts: bool,
+    ) -> Result<Tallies> {
+        let (searches, stats): Searched<K, T, C> = search_reads(
+            filename,
+            &self.parts,
+            self.alphabet,
+            shared_counts,
+        )?;
+        Ok(self.tally(&searches, stats))
+    }
+
+    fn save(
+        &self,
+        filename: &str,
+        settings: &IndexSettings,
+        names: &[String],
+        seqs: &[String],
+    ) -> Result<()> {
+        let tables: Vec<_> = self
+            .parts
+            .iter()
+            .map(|part| (&part.index.table, &part.credits))
+            .collect();
+        save_index(filename, settings, names, seqs, &tables)
+    }
+}
+
+/// The search of the reads fed to a `Feed` so far.
+struct Fed<'a, K: Kmer, T: Table<K>, C: Count> {
+    indexed: &'a Indexed<K, T, C>,
+    searches: Vec<Search<'a, K, T, Counts<C>>>,
+    stats: Stats,
+}
+
+impl<K: Kmer, T: Table<K>, C: Count> Feeder for Fed<'_, K, T, C> {
+    fn feed(&mut self, seq: &[u8]) {
+        search_read(&mut self.searches, &mut self.stats, seq);
+    }
+
+    fn finish(self: Box<Self>) -> Tallies {
+        let stats = with_lookups(&self.searches, self.stats);
+        self.indexed.tally(&self.searches, stats)
+    }
+}
+
+/// The hits found in one reads file by a search of each part, and what
+/// became of its reads.
+type Searched<'a, K, T, C> = (Vec<Search<'a, K, T, Counts<C>>>, Stats);
+
+/// Searches one reads file on all of the threads. A reader thread
+/// parses the records into batches and each worker counts hits on the
+/// batches it takes. The workers either keep their own counts, which
+/// are added up at the end, or all count into one table of atomic
+/// counters. Either way the totals are just what a single thread would
+/// have found.
+fn search_reads<'a, K: Kmer, T: Table<K>, C: Count>(
+    reads_file: &str,
+    parts: &'a [Part<K, T>],
+    alphabet: Alphabet,
+    shared_counts: bool,
+) -> Result<Searched<'a, K, T, C>> {
+    // Keep a couple of batches ready for each worker, no more. The
+    // workers hand each batch back once searched to be filled again.
+    let (sender, receiver) =
+        mpsc::sync_channel::<Batch>(2 * rayon::current_num_threads());
+    let (returns, returned) = mpsc::channel::<Batch>();
+
+    thread::scope(|scope| {
+        let reader = scope.spawn(move || -> Result<()> {
+            let mut reads = get_reader(reads_file)?;
+            let mut batch = Batch::default();
+            while let Some(rec) =
+                reads.iter_record().map_err(|source| Error::Parse {
+                    path: reads_file.to_string(),
+                    source,
+                })?
+            {
+                batch.push(rec.seq().as_bytes());
+                if batch.len() == READ_BATCH_SIZE {
+                    let mut next = returned.try_recv().unwrap_or_default();
+                    next.clear();
+                    // The workers only hang up if one of them panicked
+                    if sender.send(mem::replace(&mut batch, next)).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            if batch.len() > 0 {
+                let _ = sender.send(batch);
+            }
+            Ok(())
+        });
+
+        let batches = receiver.into_iter().par_bridge();
+        let searched = if shared_counts {
+            let counts: Vec<AtomicCounts<C>> = parts
+                .iter()
+                .map(|part| AtomicCounts::new(part.index.table.capacity()))
+                .collect();
+            let stats = batches
+                .fold(
+                    || {
+                        let searches = parts
+                            .iter()
+                            .zip(&counts)
+                            .map(|(part, counts)| {
+                                Search::with_counts(
+                                    &part.index,
+                                    alphabet,
+                                    counts,
+                                )
+                            })
+                            .collect::<Vec<_>>();
+                        (searches, Stats::default())
+                    },
+                    |searched, batch| search_batch(searched, batch, &returns),
+                )
+                .map(|(searches, stats)| with_lookups(&searches, stats))
+                .reduce(Stats::default, Stats::merge);
+
+            let searches = parts
+                .iter()
+                .zip(&counts)
+                .map(|(part, counts)| {
+                    Search::with_counts(
+                        &part.index,
+                        alphabet,
+                        counts.snapshot(),
+                    )
+                })
+                .collect();
+            (searches, stats)
+        } else {
+            let new = || {
+                let searches = parts
+                    .iter()
+                    .map(|part| Search::new(&part.index, alphabet))
+                    .collect::<Vec<_>>();
+                (searches, Stats::default())
+            };
+            let (searches, stats) = batches
+                .fold(new, |searched, batch| {
+                    search_batch(searched, batch, &returns)
+                })
+                .reduce(
+                    new,
+                    |(mut searches, stats), (others, other_stats)| {
+                        for (search, other) in
+                            searches.iter_mut().zip(&others)
+                        {
+                            search.merge(other);
+                        }
+                        (searches, stats.merge(other_stats))
+                    },
+                );
+            let stats = with_lookups(&searches, stats);
+            (searches, stats)
+        };
+
+        reader.join().unwrap_or_else(|e| panic::resume_unwind(e))?;
+        Ok(searched)
+    })
+}
+
+/// Reads passed from the reader thread to the workers, their bases end
+/// to end in one buffer that is reused from batch to batch.
+#[derive(Default)]
+struct Batch {
+    bases: Vec<u8>,
+    ends: Vec<usize>,
+}
+
+impl Batch {
+    fn push(&mut self, seq: &[u8]) {
+        self.bases.extend_from_slice(seq);
+        self.ends.push(self.bases.len());
+    }
+
+    fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    fn clear(&mut self) {
+        self.bases.clear();
+        self.ends.clear();
+    }
+
+    fn seqs(&self) -> impl Iterator<Item = &[u8]> {
+        let starts = iter::once(0).chain(self.ends.iter().copied());
+        starts
+            .zip(&self.ends)
+            .map(|(start, &end)| &self.bases[start..end])
+    }
+}
+
+/// Searches one batch of reads, then hands it back to the reader.
+fn search_batch<'a, K: Kmer, T: Table<K>, H: Hits>(
+    (mut searches, mut stats): (Vec<Search<'a, K, T, H>>, Stats),
+    batch: Batch,
+    returns: &mpsc::Sender<Batch>,
+) -> (Vec<Search<'a, K, T, H>>, Stats) {
+    for seq in batch.seqs() {
+        search_read(&mut searches, &mut stats, seq);
+    }
+    // The reader has finished if it no longer takes batches back
+    let _ = returns.send(batch);
+    (searches, stats)
+}
+
+/// Searches one read for the k-mers of every part it is long enough
+/// to hold.
+fn search_read<K: Kmer, T: Table<K>, H: Hits>(
+    searches: &mut [Search<K, T, H>],
+    stats: &mut Stats,
+    seq: &[u8],
+) {
+    stats.reads += 1;
+
+    // Reads shorter than the shortest k can't hold a junction, so they
+    // are only counted. The searches are shortest k first.
+    if searches
+        .first()
+        .is_none_or(|search| seq.len() < search.kmer_size())
+    {
+        stats.short_reads += 1;
+        return;
+    }
+
+    for search in searches {
+        search.search(seq);
+    }
+}
+
+/// The stats of reads searched by `searches`, with the lookups the
+/// searches tallied.
+fn with_lookups<K: Kmer, T: Table<K>, H: Hits>(
+    searches: &[Search<K, T, H>],
+    stats: Stats,
+) -> Stats {
+    Stats {
+        lookups: searches.iter().map(|search| search.lookups).sum(),
+        rejected: searches.iter().map(|search| search.rejected).sum(),
+        ..stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        counter::{Batch, Counter, Tally},
+        error::{Error, Rejection},
+        index::Backend,
+        search::Strand,
+    };
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const JUNCTIONS: [(&str, &str); 3] = [
+        ("one", "ACGTACGTAC"),
+        ("two", "TTTTTGGGGG"),
+        ("odd", "ACGTNCGTAC"),
+    ];
+
+    const READS: [&str; 4] = [
+        "GGACGTACGTACGG",
+        "CCCCCAAAAA",
+        "ACGTACGTACTTTTTGGGGG",
+        "ACG",
+    ];
+
+    #[test]
+    fn counter_count_seqs() {
+        let counter = Counter::builder()
+            .strand(Strand::Both)
+            .junctions(JUNCTIONS)
+            .unwrap();
+        assert_eq!(counter.names(), ["one", "two"]);
+        assert_eq!(counter.seqs(), ["ACGTACGTAC", "TTTTTGGGGG"]);
+        assert_eq!(counter.settings().kmer_sizes, [10]);
+        assert_eq!(counter.kmers(), 2);
+        assert_eq!(counter.rejected().len(), 1);
+        assert_eq!(counter.rejected()[0].0.name, "odd");
+        assert_eq!(counter.rejected()[0].1, Rejection::Ambiguous(10));
+
+        let tallies = counter.count_seqs(READS);
+        assert_eq!(
+            tallies.tallies,
+            [
+                Tally {
+                    exact: (2, 0),
+                    approx: (0, 0)
+                },
+                Tally {
+                    exact: (1, 1),
+                    approx: (0, 0)
+                },
+            ]
+        );
+        assert_eq!(tallies.stats.reads, 4);
+        assert_eq!(tallies.stats.short_reads, 1);
+
+        // Feeding the reads one at a time is the same
+        let mut feed = counter.feed();
+        for read in READS {
+            feed.feed(read.as_bytes());
+        }
+        assert_eq!(feed.finish(), tallies);
+    }
+
+    #[test]
+    fn counter_count_file() {
+        let mut reads = NamedTempFile::new().unwrap();
+        for (i, read) in READS.iter().enumerate() {
+            writeln!(reads, ">read{i}\n{read}").unwrap();
+        }
+        let filename = reads.path().to_str().unwrap();
+
+        for (backend, wide_counts, shared_counts) in [
+            (Backend::Hash, false, false),
+            (Backend::Perfect, true, true),
+        ] {
+            let counter = Counter::builder()
+                .strand(Strand::Canonical)
+                .max_mismatches(1)
+                .backend(backend)
+                .wide_counts(wide_counts)
+                .shared_counts(shared_counts)
+                .prefilter(0.01)
+                .junctions(JUNCTIONS)
+                .unwrap();
+            let tallies = counter.count_file(filename).unwrap();
+            assert_eq!(tallies, counter.count_seqs(READS));
+            assert_eq!(tallies.tallies[0].exact, (2, 0));
+        }
+    }
+
+    #[test]
+    fn counter_saves_index() {
+        let counter = Counter::builder()
+            .max_mismatches(1)
+            .junctions(JUNCTIONS)
+            .unwrap();
+        let file = NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        counter.save(filename).unwrap();
+
+        // The index settles the options it was built with
+        let loaded = Counter::builder()
+            .kmer_size(5)
+            .index_file(filename)
+            .unwrap();
+        assert_eq!(loaded.settings(), counter.settings());
+        assert_eq!(loaded.names(), counter.names());
+        assert_eq!(loaded.seqs(), counter.seqs());
+        assert_eq!(loaded.count_seqs(READS), counter.count_seqs(READS));
+    }
+
+    #[test]
+    fn counter_batch() {
+        let mut batch = Batch::default();
+        for seq in READS {
+            batch.push(seq.as_bytes());
+        }
+        batch.push(b"");
+        assert_eq!(batch.len(), 5);
+        let seqs: Vec<_> = batch.seqs().collect();
+        assert_eq!(seqs[..4], READS.map(str::as_bytes));
+        assert_eq!(seqs[4], b"");
+
+        // A batch handed back is filled again from the start
+        batch.clear();
+        batch.push(b"ACGT");
+        assert_eq!(batch.seqs().collect::<Vec<_>>(), [b"ACGT"]);
+    }
+
+    #[test]
+    fn counter_errors() {
+        let none: [(&str, &str); 0] = [];
+        assert!(matches!(
+            Counter::builder().junctions(none),
+            Err(Error::NoJunctions)
+        ));
+        assert!(matches!(
+            Counter::builder().junctions([("bad", "NNNN")]),
+            Err(Error::NoJunctions)
+        ));
+        assert!(matches!(
+            Counter::builder().kmer_size(65).junctions(JUNCTIONS),
+            Err(Error::KmerSize(65))
+        ));
+
+        let counter = Counter::builder().junctions(JUNCTIONS).unwrap();
+        let err = counter.count_file("no/such/reads.fa").unwrap_err();
+        assert!(matches!(err, Error::Io { .. }), "{err}");
+
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b">junction\nACGT\n").unwrap();
+        let filename = file.path().to_str().unwrap();
+        let err = Counter::builder().index_file(filename).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("{filename}: not a tallyman index")
+        );
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    fmt::Debug,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+/// An unsigned integer wide enough to count the hits on one k-mer.
+/// Counts saturate at their largest value rather than wrapping around.
+pub trait Count: Copy + Debug + Default + Send + Sync + Into<u64> {
+    /// The same count, for threads to add to at once.
+    type Atomic: AtomicCount<Value = Self>;
+
+    /// Adds one, unless the count is already at its largest value.
+    fn increment(&mut self);
+
+    /// Adds another count to this one, stopping at the largest value.
+    fn merge(&mut self, other: Self);
+}
+
+impl Count for u32 {
+    type Atomic = AtomicU32;
+
+    #[inline]
+    fn increment(&mut self) {
+        *self = self.saturating_add(1);
+    }
+
+    #[inline]
+    fn merge(&mut self, other: u32) {
+        *self = self.saturating_add(other);
+    }
+}
+
+impl Count for u64 {
+    type Atomic = AtomicU64;
+
+    #[inline]
+    fn increment(&mut self) {
+        *self = self.saturating_add(1);
+    }
+
+    #[inline]
+    fn merge(&mut self, other: u64) {
+        *self = self.saturating_add(other);
+    }
+}
+
+/// A count that any number of threads can add to through a shared
+/// reference. Nothing else is ordered by the counts, so relaxed
+/// ordering is enough.
+pub trait AtomicCount: Debug + Default + Send + Sync {
+    type Value: Count;
+
+    /// Adds one, unless the count is already at its largest value.
+    fn increment(&self);
+
+    /// The count as it stands.
+    fn get(&self) -> Self::Value;
+}
+
+impl AtomicCount for AtomicU32 {
+    type Value = u32;
+
+    #[inline]
+    fn increment(&self) {
+        // Refusing to go past the largest value stops the update, so a
+        // saturated count is never written again.
+        let _ =
+            self.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                n.checked_add(1)
+            });
+    }
+
+    #[inline]
+    fn get(&self) -> u32 {
+        self.load(Ordering::Relaxed)
+    }
+}
+
+impl AtomicCount for AtomicU64 {
+    type Value = u64;
+
+    #[inline]
+    fn increment(&self) {
+        let _ =
+            self.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                n.checked_add(1)
+            });
+    }
+
+    #[inline]
+    fn get(&self) -> u64 {
+        self.load(Ordering::Relaxed)
+    }
+}
+
+/// Somewhere to count the hits on each slot of a junction table.
+pub trait Hits {
+    /// Count a hit on the forward strand of a read.
+    fn inc_hits(&mut self, index: usize);
+
+    /// Count a hit on the reverse complement of a read.
+    fn inc_rc_hits(&mut self, index: usize);
+
+    fn get_hits(&self, index: usize) -> u64;
+
+    fn get_rc_hits(&self, index: usize) -> u64;
+}
+
+/// Hits on each slot of a junction table, on the forward strand and on
+/// the reverse complement of the reads.
+#[derive(Debug, Clone)]
+pub struct Counts<C: Count = u32> {
+    pub(crate) hits: Vec<C>,
+    pub(crate) rc_hits: Vec<C>,
+}
+
+impl<C: Count> Counts<C> {
+    /// Zeroed counts for a table of `capacity` slots.
+    pub fn new(capacity: usize) -> Counts<C> {
+        Counts {
+            hits: vec![C::default(); capacity],
+            rc_hits: vec![C::default(); capacity],
+        }
+    }
+
+    /// Adds the hits counted on the same table by someone else.
+    pub fn merge(&mut self, other: &Counts<C>) {
+        for (hits, other) in self.hits.iter_mut().zip(&other.hits) {
+            hits.merge(*other);
+        }
+        for (hits, other) in self.rc_hits.iter_mut().zip(&other.rc_hits) {
+            hits.merge(*other);
+        }
+    }
+}
+
+impl<C: Count> Hits for Counts<C> {
+    #[inline]
+    fn inc_hits(&mut self, index: usize) {
+        self.hits[index].increment();
+    }
+
+    #[inline]
+    fn inc_rc_hits(&mut self, index: usize) {
+        self.rc_hits[index].increment();
+    }
+
+    fn get_hits(&self, index: usize) -> u64 {
+        self.hits[index].into()
+    }
+
+    fn get_rc_hits(&self, index: usize) -> u64 {
+        self.rc_hits[index].into()
+    }
+}
+
+/// Hits on each slot of a junction table kept in atomic counters, so
+/// that every thread searching a file can count into the same ones
+/// instead of each keeping its own `Counts`.
+#[derive(Debug)]
+pub struct AtomicCounts<C: Count = u32> {
+    pub(crate) hits: Vec<C::Atomic>,
+    pub(crate) rc_hits: Vec<C::Atomic>,
+}
+
+impl<C: Count> AtomicCounts<C> {
+    /// Zeroed counts for a table of `capacity` slots.
+    pub fn new(capacity: usize) -> AtomicCounts<C> {
+        AtomicCounts {
+            hits: (0..capacity).map(|_| C::Atomic::default()).collect(),
+            rc_hits: (0..capacity).map(|_| C::Atomic::default()).collect(),
+        }
+    }
+
+    /// The counts as they stand, for output once the threads are done.
+    pub fn snapshot(&self) -> Counts<C> {
+        Counts {
+            hits: self.hits.iter().map(|hits| hits.get()).collect(),
+            rc_hits: self.rc_hits.iter().map(|hits| hits.get()).collect(),
+        }
+    }
+}
+
+/// Each thread counts through its own shared reference.
+impl<C: Count> Hits for &AtomicCounts<C> {
+    #[inline]
+    fn inc_hits(&mut self, index: usize) {
+        self.hits[index].increment();
+    }
+
+    #[inline]
+    fn inc_rc_hits(&mut self, index: usize) {
+        self.rc_hits[index].increment();
+    }
+
+    fn get_hits(&self, index: usize) -> u64 {
+        self.hits[index].get().into()
+    }
+
+    fn get_rc_hits(&self, index: usize) -> u64 {
+        self.rc_hits[index].get().into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::counts::{AtomicCounts, Counts, Hits};
+    use std::{sync::atomic::Ordering, thread};
+
+    #[test]
+    fn counts_create() {
+        let counts: Counts = Counts::new(10);
+        assert_eq!(counts.hits, [0; 10]);
+        assert_eq!(counts.rc_hits, [0; 10]);
+    }
+
+    #[test]
+    fn counts_inc_get_hits() {
+        let mut counts: Counts = Counts::new(3);
+        for index in [0, 2, 0] {
+            counts.inc_hits(index);
+        }
+        counts.inc_rc_hits(2);
+
+        assert_eq!(counts.get_hits(0), 2);
+        assert_eq!(counts.get_hits(1), 0);
+        assert_eq!(counts.get_hits(2), 1);
+        assert_eq!(counts.get_rc_hits(0), 0);
+        assert_eq!(counts.get_rc_hits(2), 1);
+    }
+
+    #[test]
+    fn counts_past_i16() {
+        let mut counts: Counts = Counts::new(2);
+        let many = i16::MAX as u64 + 10;
+        for _ in 0..many {
+            counts.inc_hits(0);
+            counts.inc_rc_hits(1);
+        }
+
+        assert_eq!(counts.get_hits(0), many);
+        assert_eq!(counts.get_rc_hits(1), many);
+        assert_eq!(counts.get_hits(1), 0);
+    }
+
+    #[test]
+    fn counts_merge() {
+        let mut counts: Counts = Counts::new(3);
+        let mut other: Counts = Counts::new(3);
+        counts.inc_hits(0);
+        other.inc_hits(0);
+        other.inc_hits(2);
+        other.inc_rc_hits(1);
+        counts.merge(&other);
+
+        assert_eq!(counts.get_hits(0), 2);
+        assert_eq!(counts.get_hits(2), 1);
+        assert_eq!(counts.get_rc_hits(1), 1);
+        assert_eq!(counts.get_rc_hits(0), 0);
+
+        // Merging saturates just like counting does
+        other.hits[2] = u32::MAX;
+        counts.merge(&other);
+        assert_eq!(counts.get_hits(2), u32::MAX as u64);
+    }
+
+    #[test]
+    fn counts_saturate() {
+        let mut counts: Counts = Counts::new(1);
+        counts.hits[0] = u32::MAX - 1;
+        for _ in 0..3 {
+            counts.inc_hits(0);
+        }
+        assert_eq!(counts.get_hits(0), u32::MAX as u64);
+
+        // A wide counter goes well past where a narrow one stops
+        let mut counts: Counts<u64> = Counts::new(1);
+        counts.hits[0] = u32::MAX as u64;
+        counts.inc_hits(0);
+        assert_eq!(counts.get_hits(0), u32::MAX as u64 + 1);
+
+        counts.hits[0] = u64::MAX;
+        counts.inc_hits(0);
+        assert_eq!(counts.get_hits(0), u64::MAX);
+    }
+
+    #[test]
+    fn atomic_counts_threads() {
+        let counts: AtomicCounts = AtomicCounts::new(4);
+        thread::scope(|scope| {
+            for thread in 0..8 {
+                let mut counts = &counts;
+                scope.spawn(move || {
+                    for i in 0..10_000 {
+                        counts.inc_hits(i % 4);
+                        counts.inc_rc_hits(thread % 4);
+                    }
+                });
+            }
+        });
+
+        let snapshot = counts.snapshot();
+        for index in 0..4 {
+            assert_eq!(snapshot.get_hits(index), 20_000);
+            assert_eq!(snapshot.get_rc_hits(index), 20_000);
+        }
+    }
+
+    #[test]
+    fn atomic_counts_saturate() {
+        let counts: AtomicCounts = AtomicCounts::new(1);
+        counts.hits[0].store(u32::MAX - 1, Ordering::Relaxed);
+        for _ in 0..3 {
+            (&counts).inc_hits(0);
+        }
+        assert_eq!((&counts).get_hits(0), u32::MAX as u64);
+
+        let counts: AtomicCounts<u64> = AtomicCounts::new(1);
+        counts.hits[0].store(u32::MAX as u64, Ordering::Relaxed);
+        (&counts).inc_hits(0);
+        assert_eq!(counts.snapshot().get_hits(0), u32::MAX as u64 + 1);
+    }
+}
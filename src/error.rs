@@ -0,0 +1,143 @@
+use crate::compress::MAX_KMER_SIZE;
+use kseq::record::ParseError;
+use std::{io, num::ParseIntError};
+use thiserror::Error;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can stop junctions from being read or indexed, or
+/// reads from being counted.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// A file could not be opened, read or written.
+    #[error("{path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /// A junctions or reads file is not FASTA or FASTQ.
+    #[error("{path}: {source}")]
+    Parse {
+        path: String,
+        #[source]
+        source: ParseError,
+    },
+
+    /// An index file could not be loaded.
+    #[error("{path}: {source}")]
+    Index {
+        path: String,
+        #[source]
+        source: IndexError,
+    },
+
+    /// The output of a count could not be read back.
+    #[error("{path}: {source}")]
+    Output {
+        path: String,
+        #[source]
+        source: OutputError,
+    },
+
+    /// An output directory holds no counts to summarize.
+    #[error(r#"No counts in "{0}""#)]
+    NoCounts(String),
+
+    /// Every junction was rejected, or there were none to begin with.
+    #[error("No junctions")]
+    NoJunctions,
+
+    #[error("kmer size {0} must be between 1 and {MAX_KMER_SIZE}")]
+    KmerSize(usize),
+
+    #[error("hash full")]
+    HashFull,
+
+    #[error("no perfect hash for {0} keys")]
+    NoPerfectHash(usize),
+}
+
+/// Why an index file could not be loaded.
+#[derive(Debug, Error)]
+pub enum IndexError {
+    #[error("not a tallyman index")]
+    NotAnIndex,
+
+    #[error(
+        "index version {0} is not supported, \
+        rebuild it with \"tallyman index\""
+    )]
+    Version(u32),
+
+    #[error("index is truncated")]
+    Truncated,
+
+    /// The file is an index, but not one that could have been written.
+    #[error("{0}")]
+    Malformed(String),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Why the output of a count could not be written or read back.
+#[derive(Debug, Error)]
+pub enum OutputError {
+    /// A reads file with no file name to name its output after.
+    #[error("not a file name")]
+    FileName,
+
+    #[error("not the output of a count")]
+    NotCounts,
+
+    /// Counts that can only be merged with others of the same format.
+    #[error("written in another format than the first")]
+    Formats,
+
+    /// Counts in Parquet or Arrow, which can't be merged.
+    #[error("only counts in a text format can be merged")]
+    NotText,
+
+    /// A count or stat that is not a number.
+    #[error(r#""{name}": {source}"#)]
+    Count {
+        name: String,
+        #[source]
+        source: ParseIntError,
+    },
+
+    /// A row with other columns than the first, as if from a count
+    /// with other options.
+    #[error(r#""{name}" has {found} counts, expected {expected}"#)]
+    Columns {
+        name: String,
+        found: usize,
+        expected: usize,
+    },
+
+    /// Stats with no hits written beside them.
+    #[error("no counts written with it")]
+    NoHits,
+}
+
+/// Why a junction was left out of the panel.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Rejection {
+    #[error("length {len} is not {kmer_size}")]
+    Length { len: usize, kmer_size: usize },
+
+    /// A junction matched at its own length is too short or too long.
+    #[error("length {0} is not between 1 and {MAX_KMER_SIZE}")]
+    Size(usize),
+
+    #[error("not {0} unambiguous bases")]
+    Ambiguous(usize),
+
+    #[error(r#"invalid base "{0}""#)]
+    InvalidBase(char),
+
+    #[error("more than {0} expansions")]
+    TooManyExpansions(usize),
+}
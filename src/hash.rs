@@ -1,217 +1,464 @@
-use anyhow::{bail, Result};
+use crate::{
+    compress::{CompressedSeq, Kmer},
+    constants::HASH_CAPACITY_MULTIPLE,
+    error::{Error, IndexError, Result},
+    index::{read_bytes, read_kmers, read_u64, write_kmers, write_u64},
+};
+use std::io::{self, Read, Write};
+
+/// A fixed set of junction k-mers, each in a slot of its own. Hits on
+/// the slots are kept apart in `Counts`.
+pub trait Table<K: Kmer>: Sized + Send + Sync {
+    /// Builds a table holding each of `keys`.
+    fn build(keys: &[K]) -> Result<Self>;
+
+    /// Find the slot holding `value`, if it is in the table.
+    fn find(&self, value: K) -> Option<usize>;
+
+    /// The number of slots, which is how many counts a search needs.
+    fn capacity(&self) -> usize;
+
+    /// Every key in the table along with its slot, in slot order.
+    fn entries(&self) -> impl Iterator<Item = (usize, K)> + '_;
+
+    /// Writes the table out so it can be read back without building it
+    /// again.
+    fn write(&self, out: &mut impl Write) -> io::Result<()>;
+
+    /// Reads back a table written by `write`.
+    fn read(input: &mut impl Read) -> Result<Self, IndexError>;
+}
 
-/// A very simple hash set implementation that uses
+/// A very simple hash set implementation that uses Robin Hood
 /// linear probing to handle collisions. This is intended
 /// to be extremely lightweight to improve performance.
+///
+/// Keys are mixed before being masked down to a slot, since the low
+/// bits of a compressed k-mer are just its last few bases. Robin Hood
+/// probing keeps every key close to its home slot, so both hits and
+/// misses stop after a probe or two.
 #[derive(Debug, Clone)]
-pub struct Hash {
-    pub(crate) key: Vec<u64>,
-    pub(crate) hits: Vec<i16>,
-    capacity: u64,
+pub struct Hash<K: Kmer = CompressedSeq> {
+    pub(crate) key: Vec<K>,
+    pub(crate) occupied: Vec<bool>,
+    capacity: usize,
+    len: usize,
 }
 
-impl Hash {
-    pub fn new(capacity: usize) -> Hash {
+impl<K: Kmer> Hash<K> {
+    /// Makes room for at least `capacity` keys, rounded up to a
+    /// power of two so a slot can be picked with a mask.
+    pub fn new(capacity: usize) -> Hash<K> {
+        let capacity = capacity.max(1).next_power_of_two();
         Hash {
-            key: vec![0; capacity],
-            hits: vec![-1; capacity],
-            capacity: capacity as u64,
+            key: vec![K::default(); capacity],
+            occupied: vec![false; capacity],
+            capacity,
+            len: 0,
         }
     }
 
+    /// The slot `value` would take in an empty table.
+    #[inline]
+    fn home(&self, value: K) -> usize {
+        value.mix() as usize & (self.capacity - 1)
+    }
+
+    /// How far the key at `index` was pushed past its home slot.
+    #[inline]
+    fn distance(&self, index: usize) -> usize {
+        index.wrapping_sub(self.home(self.key[index])) & (self.capacity - 1)
+    }
+
     /// Add the given value to the set.
-    /// There is a problem that the `value` legit could be 0
-    /// in the case of a poly-A sequence, so we init the hits
-    /// to -1 and check that hash for values that have been taken
-    pub fn add(&mut self, value: u64) -> Result<()> {
-        // We may now cast hv to a usize because we're sure
-        // that it is < self.size and will therefore fit.
-        let hv_index = (value % self.capacity) as usize;
-        let mut probed_index = hv_index;
-
-        // Find the next empty slot (this is the linear probing bit).
-        // Hits start at -1 and get set to 0 when filled!
-        while self.hits[probed_index] == 0 {
-            probed_index += 1;
-
-            if probed_index >= self.capacity as usize {
-                probed_index = 0;
-            }
+    /// The `value` could legitimately be 0 in the case of a
+    /// poly-A sequence, so which slots have been taken is
+    /// kept apart from the keys.
+    pub fn add(&mut self, value: K) -> Result<()> {
+        if self.len == self.capacity {
+            return Err(Error::HashFull);
+        }
 
-            if probed_index == hv_index {
-                bail!("hash full");
+        let mut value = value;
+        let mut index = self.home(value);
+        let mut distance = 0;
+
+        // Walk to the next empty slot, handing our place over to any
+        // key that is closer to home than we are and carrying that
+        // key on in its stead.
+        while self.occupied[index] {
+            let resident = self.distance(index);
+            if resident < distance {
+                std::mem::swap(&mut self.key[index], &mut value);
+                distance = resident;
             }
+
+            index = (index + 1) & (self.capacity - 1);
+            distance += 1;
         }
 
-        self.key[probed_index] = value;
-        self.hits[probed_index] = 0;
+        self.key[index] = value;
+        self.occupied[index] = true;
+        self.len += 1;
         Ok(())
     }
 
-    /// This is too slow to use!
-    /// Find `value` key
-    pub fn find(&mut self, value: u64) -> Option<usize> {
-        let start = (value % self.capacity) as usize;
-        let mut probed_index = start;
-
-        //if self.key[probed_index] == value {
-        //    return Some(probed_index);
-        //} else {
-        //    while self.key[probed_index] != 0 {
-        //        probed_index += 1;
-
-        //        // We've gone off the end
-        //        if probed_index >= self.capacity as usize {
-        //            probed_index = 0;
-        //        }
-
-        //        // We've looped around to the beginning
-        //        if probed_index == start {
-        //            break;
-        //        }
-
-        //        if self.key[probed_index] == value {
-        //            return Some(probed_index);
-        //        }
-        //    }
-        //}
-
-        loop {
-            if self.key[probed_index] == value {
-                return Some(probed_index);
-            } else {
-                probed_index += 1;
-
-                // We've gone off the end
-                if probed_index >= self.capacity as usize {
-                    probed_index = 0;
-                }
+    /// Find `value` key, also returning how many slots were looked at.
+    /// Emptiness is only ever decided by `occupied`, never by the
+    /// key, since every key (0 for poly-A included) is a k-mer. A
+    /// search can also stop at any key closer to its home than
+    /// `value` would be, since Robin Hood insertion would have put
+    /// `value` there instead.
+    #[inline]
+    fn locate(&self, value: K) -> (Option<usize>, usize) {
+        let mut index = self.home(value);
+
+        for distance in 0..self.capacity {
+            if !self.occupied[index] || self.distance(index) < distance {
+                return (None, distance + 1);
+            }
 
-                // We've looped around to the beginning
-                if probed_index == start {
-                    break;
-                }
+            if self.key[index] == value {
+                return (Some(index), distance + 1);
             }
+
+            index = (index + 1) & (self.capacity - 1);
         }
 
-        None
+        (None, self.capacity)
     }
+}
 
-    pub fn inc_hits(&mut self, value: u64) {
-        let start = (value % self.capacity) as usize;
-        let mut probed_index = start;
+impl<K: Kmer> Table<K> for Hash<K> {
+    fn build(keys: &[K]) -> Result<Self> {
+        let mut hash = Hash::new(keys.len() * HASH_CAPACITY_MULTIPLE);
+        for key in keys {
+            hash.add(*key)?;
+        }
+        Ok(hash)
+    }
 
-        if self.key[probed_index] == value {
-            self.hits[probed_index] += 1;
-        } else {
-            // Linear probing
-            while self.key[probed_index] != 0 {
-                probed_index += 1;
+    #[inline]
+    fn find(&self, value: K) -> Option<usize> {
+        self.locate(value).0
+    }
 
-                // We've gone off the end
-                if probed_index >= self.capacity as usize {
-                    probed_index = 0;
-                }
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
 
-                // We've looped around to the beginning
-                if probed_index == start {
-                    break;
-                }
+    fn entries(&self) -> impl Iterator<Item = (usize, K)> + '_ {
+        self.key
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| self.occupied[*index])
+            .map(|(index, key)| (index, *key))
+    }
 
-                //If we are at an index that matches the DCE we're looking
-                //for, then increment the hits array at that index and stop
-                if self.key[probed_index] == value {
-                    self.hits[probed_index] += 1;
-                    break;
-                }
-            }
-        }
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        write_kmers(out, &self.key)?;
+        let occupied: Vec<u8> = self
+            .occupied
+            .iter()
+            .map(|occupied| *occupied as u8)
+            .collect();
+        write_u64(out, occupied.len() as u64)?;
+        out.write_all(&occupied)?;
+        Ok(())
     }
 
-    pub fn get_hits(&mut self, value: u64) -> Option<u64> {
-        self.find(value)
-            .and_then(|index| Some(self.hits[index] as u64))
+    fn read(input: &mut impl Read) -> Result<Self, IndexError> {
+        let key: Vec<K> = read_kmers(input)?;
+        let len = read_u64(input)?;
+        let occupied: Vec<bool> = read_bytes(input, len)?
+            .iter()
+            .map(|occupied| *occupied != 0)
+            .collect();
+
+        let capacity = key.len();
+        if occupied.len() != capacity || !capacity.is_power_of_two() {
+            return Err(IndexError::Malformed(format!(
+                "hash table of {capacity} slots is malformed"
+            )));
+        }
+
+        Ok(Hash {
+            len: occupied.iter().filter(|occupied| **occupied).count(),
+            key,
+            occupied,
+            capacity,
+        })
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::hash::Hash;
+    use crate::compress::{compress_seq, WideCompressedSeq};
+    use crate::constants::HASH_CAPACITY_MULTIPLE;
+    use crate::hash::{Hash, Table};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::fs;
+
+    /// The first `n` keys other than `key` that share its home slot.
+    fn colliding(hash: &Hash, key: u64, n: usize) -> Vec<u64> {
+        (0..)
+            .filter(|other| {
+                *other != key && hash.home(*other) == hash.home(key)
+            })
+            .take(n)
+            .collect()
+    }
 
     #[test]
     fn hash_create() {
-        let hash = Hash::new(10);
-        assert_eq!(hash.capacity, 10);
-        assert_eq!(hash.key.len(), 10);
-        assert_eq!(hash.hits.len(), 10);
+        // Capacity is rounded up to a power of two
+        let hash: Hash = Hash::new(10);
+        assert_eq!(hash.capacity, 16);
+        assert_eq!(hash.key.len(), 16);
+        assert_eq!(hash.occupied.len(), 16);
 
-        for i in 0..10 {
+        for i in 0..16 {
             assert_eq!(hash.key[i], 0);
-            assert_eq!(hash.hits[i], -1);
+            assert!(!hash.occupied[i]);
         }
+
+        let hash: Hash = Hash::new(0);
+        assert_eq!(hash.capacity, 1);
+    }
+
+    #[test]
+    fn hash_mixes_keys() {
+        // Keys that differ only in their first base still spread out
+        let hash: Hash = Hash::new(1024);
+        let homes: std::collections::HashSet<_> =
+            (0..64).map(|base: u64| hash.home(base << 58)).collect();
+        assert!(homes.len() > 32);
     }
 
     #[test]
     fn hash_add() {
-        let mut hash = Hash::new(10);
+        let mut hash: Hash = Hash::new(10);
         let _ = hash.add(10);
-        assert_eq!(hash.key[0], 10);
-        assert_eq!(hash.hits[0], 0);
-
-        let _ = hash.add(11);
-        assert_eq!(hash.key[1], 11);
-        assert_eq!(hash.hits[1], 0);
-
-        // 0 will collide with 10 and should be pushed over 2 places
-        let _ = hash.add(0);
-        assert_eq!(hash.key[2], 0);
-        assert_eq!(hash.hits[2], 0);
+        let home = hash.home(10);
+        assert_eq!(hash.key[home], 10);
+        assert!(hash.occupied[home]);
+
+        // A key whose home is the next slot over
+        let next = (0..)
+            .find(|key| hash.home(*key) == (home + 1) % 16)
+            .unwrap();
+        let _ = hash.add(next);
+        assert_eq!(hash.key[(home + 1) % 16], next);
+
+        // A key that collides with 10 takes the next slot from the
+        // key that is already home, which moves one further along
+        let other = colliding(&hash, 10, 1)[0];
+        let _ = hash.add(other);
+        assert_eq!(hash.key[home], 10);
+        assert_eq!(hash.key[(home + 1) % 16], other);
+        assert_eq!(hash.key[(home + 2) % 16], next);
+        assert_eq!(hash.occupied.iter().filter(|taken| **taken).count(), 3);
+    }
 
-        // 19 should be placed at the end
-        let _ = hash.add(19);
-        assert_eq!(hash.key[9], 19);
-        assert_eq!(hash.hits[9], 0);
+    #[test]
+    fn hash_add_wraps() {
+        // Keys pushed off the end of the table wrap to the start
+        let mut hash: Hash = Hash::new(16);
+        let last = (0..).find(|key| hash.home(*key) == 15).unwrap();
+        let keys = [vec![last], colliding(&hash, last, 2)].concat();
+        for key in &keys {
+            let _ = hash.add(*key);
+        }
 
-        // 9 will conflict with 19 and will wrap around
-        let _ = hash.add(9);
-        assert_eq!(hash.key[3], 9);
-        assert_eq!(hash.hits[3], 0);
+        assert_eq!(hash.key[15], keys[0]);
+        assert_eq!(hash.key[0], keys[1]);
+        assert_eq!(hash.key[1], keys[2]);
+        for (index, key) in [15, 0, 1].iter().zip(&keys) {
+            assert_eq!(hash.find(*key), Some(*index));
+        }
     }
 
     #[test]
     fn hash_find() {
-        let mut hash = Hash::new(10);
+        let mut hash: Hash = Hash::new(10);
         for val in &[10, 11, 0] {
             let _ = hash.add(*val);
         }
 
-        let res = hash.find(10);
-        assert_eq!(res, Some(0));
-
-        let res = hash.find(11);
-        assert_eq!(res, Some(1));
-
-        let res = hash.find(0);
-        assert_eq!(res, Some(2));
+        for val in &[10, 11, 0] {
+            let res = hash.find(*val);
+            assert!(res.is_some());
+            assert_eq!(hash.key[res.unwrap()], *val);
+        }
 
         let res = hash.find(12);
         assert!(res.is_none());
     }
 
     #[test]
-    fn hash_inc_get_hits() {
-        let mut hash = Hash::new(10);
-        for val in &[10, 11, 0] {
+    fn hash_build() {
+        let hash: Hash = Table::build(&[10, 11, 0]).unwrap();
+        assert_eq!(hash.capacity(), 32);
+
+        let entries: Vec<_> = hash.entries().collect();
+        assert_eq!(entries.len(), 3);
+        for (index, key) in entries {
+            assert_eq!(hash.find(key), Some(index));
+        }
+        assert!(hash.find(1).is_none());
+    }
+
+    #[test]
+    fn hash_wide_keys() {
+        let mut hash: Hash<WideCompressedSeq> = Hash::new(10);
+        let wide = u128::MAX - 5;
+        for val in &[wide, 11, wide - 10, wide ^ (1 << 64)] {
+            let _ = hash.add(*val);
+        }
+
+        // The high half of a wide key is mixed in too
+        assert_ne!(hash.home(wide), hash.home(wide ^ (1 << 64)));
+
+        for val in &[wide, 11, wide - 10, wide ^ (1 << 64)] {
+            assert_eq!(hash.key[hash.find(*val).unwrap()], *val);
+        }
+        assert!(hash.find(1).is_none());
+    }
+
+    #[test]
+    fn hash_poly_a_collisions() {
+        // Poly-A is key 0, which is also what an empty slot holds
+        let mut hash: Hash = Hash::new(10);
+        let others = colliding(&hash, 0, 3);
+        for val in [0, others[0], others[1]] {
+            let _ = hash.add(val);
+        }
+
+        let home = hash.home(0);
+        assert_eq!(hash.find(0), Some(home));
+        assert_eq!(hash.find(others[0]), Some((home + 1) % 16));
+        assert_eq!(hash.find(others[1]), Some((home + 2) % 16));
+        assert!(hash.find(others[2]).is_none());
+    }
+
+    #[test]
+    fn hash_poly_a_missing() {
+        // An empty slot must not pass for a poly-A key
+        let mut hash: Hash = Hash::new(10);
+        for val in colliding(&hash, 0, 2) {
+            let _ = hash.add(val);
+        }
+        assert!(hash.find(0).is_none());
+
+        // Misses stop within a slot or two of their own
+        assert!(hash.locate(0).1 <= 3);
+        assert!(hash.find(20).is_none());
+    }
+
+    #[test]
+    fn hash_full_misses() {
+        // A full table has no empty slot to stop at
+        let mut hash: Hash = Hash::new(4);
+        for val in &[0, 1, 2, 3] {
             let _ = hash.add(*val);
         }
+        assert!(hash.add(4).is_err());
+        assert!(hash.find(4).is_none());
+        for val in &[0, 1, 2, 3] {
+            assert!(hash.find(*val).is_some());
+        }
+    }
+
+    /// Mean and longest probe lengths when looking each of `queries`
+    /// up, using `value % capacity` and plain linear probing as the
+    /// junction table once did.
+    fn legacy_probes(keys: &[u64], queries: &[u64]) -> (f64, usize) {
+        let capacity = (keys.len() * HASH_CAPACITY_MULTIPLE) as u64;
+        let mut slots = vec![None; capacity as usize];
+        for key in keys {
+            let mut index = (key % capacity) as usize;
+            while slots[index].is_some() {
+                index = (index + 1) % slots.len();
+            }
+            slots[index] = Some(*key);
+        }
+
+        let probes: Vec<usize> = queries
+            .iter()
+            .map(|query| {
+                let mut index = (query % capacity) as usize;
+                let mut probes = 1;
+                while slots[index].is_some_and(|key| key != *query) {
+                    index = (index + 1) % slots.len();
+                    probes += 1;
+                }
+                probes
+            })
+            .collect();
+        summarize(&probes)
+    }
 
-        for val in &[10, 11, 10, 11, 11, 0] {
-            let _ = hash.inc_hits(*val);
+    /// Mean and longest probe lengths when looking each of `queries`
+    /// up in a `Hash` of `keys`.
+    fn robin_hood_probes(keys: &[u64], queries: &[u64]) -> (f64, usize) {
+        let mut hash: Hash = Hash::new(keys.len() * HASH_CAPACITY_MULTIPLE);
+        for key in keys {
+            hash.add(*key).unwrap();
         }
 
-        assert_eq!(hash.get_hits(10), Some(2));
-        assert_eq!(hash.get_hits(11), Some(3));
-        assert_eq!(hash.get_hits(0), Some(1));
-        assert!(hash.get_hits(1).is_none());
+        let probes: Vec<usize> =
+            queries.iter().map(|query| hash.locate(*query).1).collect();
+        summarize(&probes)
+    }
+
+    fn summarize(probes: &[usize]) -> (f64, usize) {
+        let total: usize = probes.iter().sum();
+        let longest = probes.iter().copied().max().unwrap_or_default();
+        (total as f64 / probes.len() as f64, longest)
+    }
+
+    /// Compares probe lengths of the old and new junction tables on the
+    /// fixture junctions. Run `make fixtures` first, then
+    /// `cargo test --release bench_probe_lengths -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_probe_lengths() {
+        let fixture = fs::read_to_string("fixtures/test-dna.fasta")
+            .expect("run `make fixtures` first");
+        let seqs: Vec<&str> = fixture
+            .lines()
+            .filter(|line| !line.starts_with('>'))
+            .collect();
+
+        // The fixture is random, so also try a low-complexity set whose
+        // junctions all end in the same bases.
+        let random: Vec<u64> = seqs
+            .iter()
+            .filter_map(|seq| compress_seq(seq, 32))
+            .collect();
+        let shared_end: Vec<u64> = seqs
+            .iter()
+            .filter_map(|seq| {
+                compress_seq(&format!("{}{}", &seq[8..], "A".repeat(8)), 32)
+            })
+            .collect();
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let misses: Vec<u64> = (0..100_000).map(|_| rng.gen()).collect();
+
+        println!("set\tlookups\tscheme\tmean\tlongest");
+        for (name, keys) in [("fixture", random), ("shared_end", shared_end)]
+        {
+            for (lookups, queries) in [("hits", &keys), ("misses", &misses)] {
+                for (scheme, (mean, longest)) in [
+                    ("legacy", legacy_probes(&keys, queries)),
+                    ("robin_hood", robin_hood_probes(&keys, queries)),
+                ] {
+                    println!(
+                        "{name}\t{lookups}\t{scheme}\t{mean:.3}\t{longest}"
+                    );
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,580 @@
+use crate::{
+    compress::{CompressedSeq, Kmer, WideCompressedSeq},
+    error::{Error, IndexError, Result},
+    hash::Table,
+    search::Strand,
+};
+use clap::ValueEnum;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+/// The first bytes of every index file.
+const MAGIC: &[u8; 8] = b"TALLYIDX";
+
+/// The layout of the index files written now. Files written with any
+/// other version are refused rather than misread.
+pub const INDEX_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// An open-addressing hash table, quickest to build
+    #[default]
+    Hash,
+    /// A minimal perfect hash, with no empty slots to take up memory
+    Perfect,
+}
+
+/// Where the hits on one k-mer are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credit {
+    pub junction: usize,
+    pub flipped: bool,
+    pub exact: bool,
+}
+
+/// The options an index was built with. Reads can only be counted
+/// against it the same way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexSettings {
+    /// The length of the k-mers in each table, shortest first
+    pub kmer_sizes: Vec<usize>,
+    pub strand: Strand,
+    pub max_mismatches: u8,
+    pub backend: Backend,
+}
+
+/// The start of an index file, telling how to load the rest of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexHeader {
+    /// The longest k-mer the keys of the index can hold, and so which
+    /// kind of k-mer it must be loaded with
+    pub key_size: usize,
+    pub settings: IndexSettings,
+}
+
+/// The table of the junction k-mers of one size, and the junctions
+/// credited with each of them.
+#[derive(Debug)]
+pub struct SavedTable<K: Kmer, T: Table<K>> {
+    pub table: T,
+    pub credits: HashMap<K, Vec<Credit>>,
+}
+
+/// Junction tables saved with everything needed to report their hits:
+/// the junction names and a table for each k-mer size.
+#[derive(Debug)]
+pub struct SavedIndex<K: Kmer, T: Table<K>> {
+    pub settings: IndexSettings,
+    pub names: Vec<String>,
+    pub seqs: Vec<String>,
+    /// A table for each of `settings.kmer_sizes`
+    pub tables: Vec<SavedTable<K, T>>,
+}
+
+impl<K: Kmer, T: Table<K>> SavedIndex<K, T> {
+    /// Writes the index to `filename`.
+    pub fn save(&self, filename: &str) -> Result<()> {
+        let tables: Vec<_> = self
+            .tables
+            .iter()
+            .map(|saved| (&saved.table, &saved.credits))
+            .collect();
+        save_index(filename, &self.settings, &self.names, &self.seqs, &tables)
+    }
+
+    /// Reads back an index written by `save`.
+    pub fn load(filename: &str) -> Result<SavedIndex<K, T>> {
+        let file = File::open(filename).map_err(|source| Error::Io {
+            path: filename.to_string(),
+            source,
+        })?;
+        let mut input = BufReader::new(file);
+
+        let mut read = || -> Result<SavedIndex<K, T>, IndexError> {
+            let IndexHeader { key_size, settings } = read_header(&mut input)?;
+            if key_size != K::MAX_KMER_SIZE {
+                return Err(malformed(&format!(
+                    "keys hold {key_size} bases, not {}",
+                    K::MAX_KMER_SIZE
+                )));
+            }
+
+            let names = read_strings(&mut input)?;
+            let seqs = read_strings(&mut input)?;
+            if seqs.len() != names.len() {
+                return Err(malformed(&format!(
+                    "{} sequences for {} junctions",
+                    seqs.len(),
+                    names.len()
+                )));
+            }
+
+            let mut tables = vec![];
+            for _ in &settings.kmer_sizes {
+                let table = T::read(&mut input)?;
+                let credits = read_credits(&mut input, names.len())?;
+                tables.push(SavedTable { table, credits });
+            }
+
+            Ok(SavedIndex {
+                settings,
+                names,
+                seqs,
+                tables,
+            })
+        };
+
+        read().map_err(|source| Error::Index {
+            path: filename.to_string(),
+            source,
+        })
+    }
+}
+
+/// Writes an index to `filename`: the junction names and sequences,
+/// then for each of `settings.kmer_sizes` a table and the junctions
+/// credited with each of its k-mers, to be loaded as a `SavedIndex`.
+pub fn save_index<K: Kmer, T: Table<K>>(
+    filename: &str,
+    settings: &IndexSettings,
+    names: &[String],
+    seqs: &[String],
+    tables: &[(&T, &HashMap<K, Vec<Credit>>)],
+) -> Result<()> {
+    let write = || -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(filename)?);
+
+        write_header::<K>(&mut out, settings)?;
+        write_strings(&mut out, names)?;
+        write_strings(&mut out, seqs)?;
+
+        for (table, credits) in tables {
+            table.write(&mut out)?;
+            write_credits(&mut out, credits)?;
+        }
+
+        out.flush()
+    };
+
+    write().map_err(|source| Error::Io {
+        path: filename.to_string(),
+        source,
+    })
+}
+
+/// The header of an index, read without loading the rest of it, so the
+/// right kinds of k-mer and table can be picked to load it with.
+pub fn index_header(filename: &str) -> Result<IndexHeader> {
+    let file = File::open(filename).map_err(|source| Error::Io {
+        path: filename.to_string(),
+        source,
+    })?;
+    read_header(&mut BufReader::new(file)).map_err(|source| Error::Index {
+        path: filename.to_string(),
+        source,
+    })
+}
+
+fn write_credits<K: Kmer>(
+    out: &mut impl Write,
+    credits: &HashMap<K, Vec<Credit>>,
+) -> io::Result<()> {
+    // Sorted so the same panel always makes the same file
+    let mut credits: Vec<_> = credits.iter().collect();
+    credits.sort_unstable_by_key(|(key, _)| **key);
+    write_u64(out, credits.len() as u64)?;
+    for (key, credits) in credits {
+        out.write_all(key.to_bytes().as_ref())?;
+        write_u64(out, credits.len() as u64)?;
+        for credit in credits {
+            write_u64(out, credit.junction as u64)?;
+            out.write_all(&[
+                credit.flipped as u8 | (credit.exact as u8) << 1
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+fn read_credits<K: Kmer>(
+    input: &mut impl Read,
+    junctions: usize,
+) -> Result<HashMap<K, Vec<Credit>>, IndexError> {
+    let num_keys = read_u64(input)?;
+    let mut credits = HashMap::new();
+    for _ in 0..num_keys {
+        let key = read_kmer(input)?;
+        let num_credits = read_u64(input)?;
+        let mut key_credits = vec![];
+        for _ in 0..num_credits {
+            let junction = read_u64(input)? as usize;
+            if junction >= junctions {
+                return Err(malformed(&format!(
+                    "junction {junction} has no name"
+                )));
+            }
+            let flags = read_bytes(input, 1)?[0];
+            key_credits.push(Credit {
+                junction,
+                flipped: flags & 1 != 0,
+                exact: flags & 2 != 0,
+            });
+        }
+        credits.insert(key, key_credits);
+    }
+    Ok(credits)
+}
+
+fn write_header<K: Kmer>(
+    out: &mut impl Write,
+    settings: &IndexSettings,
+) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&INDEX_VERSION.to_le_bytes())?;
+    write_u64(out, K::MAX_KMER_SIZE as u64)?;
+    write_u64(out, settings.kmer_sizes.len() as u64)?;
+    for kmer_size in &settings.kmer_sizes {
+        write_u64(out, *kmer_size as u64)?;
+    }
+
+    let strand = match settings.strand {
+        Strand::Forward => 0,
+        Strand::Reverse => 1,
+        Strand::Both => 2,
+        Strand::Canonical => 3,
+    };
+    let backend = match settings.backend {
+        Backend::Hash => 0,
+        Backend::Perfect => 1,
+    };
+    out.write_all(&[strand, settings.max_mismatches, backend])
+}
+
+fn read_header(input: &mut impl Read) -> Result<IndexHeader, IndexError> {
+    let mut magic = [0; 8];
+    if input.read_exact(&mut magic).is_err() || &magic != MAGIC {
+        return Err(IndexError::NotAnIndex);
+    }
+
+    let mut version = [0; 4];
+    input
+        .read_exact(&mut version)
+        .map_err(|_| IndexError::Truncated)?;
+    let version = u32::from_le_bytes(version);
+    if version != INDEX_VERSION {
+        return Err(IndexError::Version(version));
+    }
+
+    let key_size = read_u64(input)? as usize;
+    if ![
+        CompressedSeq::MAX_KMER_SIZE,
+        WideCompressedSeq::MAX_KMER_SIZE,
+    ]
+    .contains(&key_size)
+    {
+        return Err(malformed(&format!("keys of {key_size} bases")));
+    }
+
+    // One table for each size, from shortest to longest
+    let num_sizes = read_u64(input)?;
+    if !(1..=key_size as u64).contains(&num_sizes) {
+        return Err(malformed(&format!("{num_sizes} k-mer sizes")));
+    }
+    let mut kmer_sizes = vec![];
+    for _ in 0..num_sizes {
+        let kmer_size = read_u64(input)? as usize;
+        if !(1..=key_size).contains(&kmer_size)
+            || kmer_sizes.last().is_some_and(|last| *last >= kmer_size)
+        {
+            return Err(malformed(&format!("k-mer size {kmer_size}")));
+        }
+        kmer_sizes.push(kmer_size);
+    }
+
+    let codes = read_bytes(input, 3)?;
+    let strand = match codes[0] {
+        0 => Strand::Forward,
+        1 => Strand::Reverse,
+        2 => Strand::Both,
+        3 => Strand::Canonical,
+        code => return Err(malformed(&format!("unknown strand {code}"))),
+    };
+    let backend = match codes[2] {
+        0 => Backend::Hash,
+        1 => Backend::Perfect,
+        code => return Err(malformed(&format!("unknown backend {code}"))),
+    };
+
+    Ok(IndexHeader {
+        key_size,
+        settings: IndexSettings {
+            kmer_sizes,
+            strand,
+            max_mismatches: codes[1],
+            backend,
+        },
+    })
+}
+
+fn malformed(reason: &str) -> IndexError {
+    IndexError::Malformed(reason.to_string())
+}
+
+pub(crate) fn write_u64(out: &mut impl Write, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn read_u64(input: &mut impl Read) -> Result<u64, IndexError> {
+    let mut bytes = [0; 8];
+    input
+        .read_exact(&mut bytes)
+        .map_err(|_| IndexError::Truncated)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Reads exactly `len` bytes, without trusting `len` enough to set
+/// aside room for them all up front.
+pub(crate) fn read_bytes(
+    input: &mut impl Read,
+    len: u64,
+) -> Result<Vec<u8>, IndexError> {
+    let mut bytes = vec![];
+    input.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(IndexError::Truncated);
+    }
+    Ok(bytes)
+}
+
+fn write_strings(out: &mut impl Write, strings: &[String]) -> io::Result<()> {
+    write_u64(out, strings.len() as u64)?;
+    for string in strings {
+        write_u64(out, string.len() as u64)?;
+        out.write_all(string.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_strings(input: &mut impl Read) -> Result<Vec<String>, IndexError> {
+    let len = read_u64(input)?;
+    let mut strings = vec![];
+    for _ in 0..len {
+        let len = read_u64(input)?;
+        let string = String::from_utf8(read_bytes(input, len)?)
+            .map_err(|e| malformed(&e.to_string()))?;
+        strings.push(string);
+    }
+    Ok(strings)
+}
+
+pub(crate) fn write_kmers<K: Kmer>(
+    out: &mut impl Write,
+    kmers: &[K],
+) -> io::Result<()> {
+    write_u64(out, kmers.len() as u64)?;
+    for kmer in kmers {
+        out.write_all(kmer.to_bytes().as_ref())?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_kmer<K: Kmer>(
+    input: &mut impl Read,
+) -> Result<K, IndexError> {
+    let mut bytes = K::Bytes::default();
+    input
+        .read_exact(bytes.as_mut())
+        .map_err(|_| IndexError::Truncated)?;
+    Ok(K::from_bytes(bytes))
+}
+
+pub(crate) fn read_kmers<K: Kmer>(
+    input: &mut impl Read,
+) -> Result<Vec<K>, IndexError> {
+    let len = read_u64(input)?;
+    let width = K::Bytes::default().as_ref().len();
+    let bytes = read_bytes(input, len.saturating_mul(width as u64))?;
+    Ok(bytes
+        .chunks_exact(width)
+        .map(|chunk| {
+            let mut bytes = K::Bytes::default();
+            bytes.as_mut().copy_from_slice(chunk);
+            K::from_bytes(bytes)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compress::WideCompressedSeq,
+        hash::{Hash, Table},
+        index::{
+            index_header, Backend, Credit, IndexHeader, IndexSettings,
+            SavedIndex, SavedTable, INDEX_VERSION,
+        },
+        perfect::PerfectHash,
+        search::Strand,
+    };
+    use std::{collections::HashMap, fs};
+    use tempfile::NamedTempFile;
+
+    /// An index with a table of `keys` for each k-mer size, crediting
+    /// each key to a junction of its own.
+    fn saved<K: crate::compress::Kmer, T: Table<K>>(
+        keys: &[K],
+        settings: IndexSettings,
+    ) -> SavedIndex<K, T> {
+        let mut credits = HashMap::new();
+        for (junction, key) in keys.iter().enumerate() {
+            credits.insert(
+                *key,
+                vec![Credit {
+                    junction,
+                    flipped: junction % 2 == 1,
+                    exact: junction % 3 != 2,
+                }],
+            );
+        }
+
+        SavedIndex {
+            tables: settings
+                .kmer_sizes
+                .iter()
+                .map(|_| SavedTable {
+                    table: T::build(keys).unwrap(),
+                    credits: credits.clone(),
+                })
+                .collect(),
+            settings,
+            names: (0..keys.len()).map(|i| format!("junction {i}")).collect(),
+            seqs: (0..keys.len()).map(|i| "ACGT".repeat(i)).collect(),
+        }
+    }
+
+    #[test]
+    fn index_round_trip() {
+        let settings = IndexSettings {
+            kmer_sizes: vec![15, 21],
+            strand: Strand::Canonical,
+            max_mismatches: 1,
+            backend: Backend::Hash,
+        };
+        // Poly-A included
+        let keys: Vec<u64> = vec![0, 7, 1 << 40, 12345];
+        let index: SavedIndex<u64, Hash> = saved(&keys, settings.clone());
+
+        let file = NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        index.save(filename).unwrap();
+        assert_eq!(
+            index_header(filename).unwrap(),
+            IndexHeader {
+                key_size: 32,
+                settings: settings.clone()
+            }
+        );
+
+        let loaded: SavedIndex<u64, Hash> =
+            SavedIndex::load(filename).unwrap();
+        assert_eq!(loaded.settings, settings);
+        assert_eq!(loaded.names, index.names);
+        assert_eq!(loaded.seqs, index.seqs);
+        assert_eq!(loaded.tables.len(), 2);
+        for (loaded, saved) in loaded.tables.iter().zip(&index.tables) {
+            assert_eq!(loaded.credits, saved.credits);
+            assert_eq!(loaded.table.key, saved.table.key);
+            assert_eq!(loaded.table.occupied, saved.table.occupied);
+            for key in &keys {
+                assert_eq!(loaded.table.find(*key), saved.table.find(*key));
+            }
+            assert!(loaded.table.find(1).is_none());
+        }
+    }
+
+    #[test]
+    fn index_round_trip_perfect_wide() {
+        let settings = IndexSettings {
+            kmer_sizes: vec![50],
+            strand: Strand::Both,
+            max_mismatches: 0,
+            backend: Backend::Perfect,
+        };
+        let wide = u128::MAX >> 28;
+        let keys: Vec<WideCompressedSeq> = vec![wide, 11, wide - 10, 0];
+        let index: SavedIndex<WideCompressedSeq, PerfectHash<_>> =
+            saved(&keys, settings.clone());
+
+        let file = NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        index.save(filename).unwrap();
+
+        let loaded: SavedIndex<WideCompressedSeq, PerfectHash<_>> =
+            SavedIndex::load(filename).unwrap();
+        assert_eq!(loaded.settings, settings);
+        let (loaded, index) = (&loaded.tables[0], &index.tables[0]);
+        assert_eq!(loaded.credits, index.credits);
+        for key in &keys {
+            assert_eq!(loaded.table.find(*key), index.table.find(*key));
+        }
+        assert!(loaded.table.find(1).is_none());
+    }
+
+    #[test]
+    fn index_rejects_bad_files() {
+        let settings = IndexSettings {
+            kmer_sizes: vec![21],
+            strand: Strand::Forward,
+            max_mismatches: 0,
+            backend: Backend::Hash,
+        };
+        let index: SavedIndex<u64, Hash> = saved(&[1, 2, 3], settings);
+        let file = NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        index.save(filename).unwrap();
+        let bytes = fs::read(filename).unwrap();
+
+        // Not an index at all
+        fs::write(filename, b">junction\nACGT\n").unwrap();
+        let err = index_header(filename).unwrap_err().to_string();
+        assert!(err.ends_with("not a tallyman index"), "{err}");
+
+        // Some other version
+        let mut other = bytes.clone();
+        other[8..12].copy_from_slice(&(INDEX_VERSION + 1).to_le_bytes());
+        fs::write(filename, &other).unwrap();
+        let err = index_header(filename).unwrap_err().to_string();
+        assert!(err.contains("is not supported"), "{err}");
+
+        // A k-mer size no table could have
+        let mut other = bytes.clone();
+        other[28..36].copy_from_slice(&33u64.to_le_bytes());
+        fs::write(filename, &other).unwrap();
+        let err = index_header(filename).unwrap_err().to_string();
+        assert!(err.ends_with("k-mer size 33"), "{err}");
+
+        // Keys of no kind of k-mer
+        let mut other = bytes.clone();
+        other[12..20].copy_from_slice(&48u64.to_le_bytes());
+        fs::write(filename, &other).unwrap();
+        let err = index_header(filename).unwrap_err().to_string();
+        assert!(err.ends_with("keys of 48 bases"), "{err}");
+
+        // Keys of another kind of k-mer than it is loaded with
+        fs::write(filename, &bytes).unwrap();
+        let err = SavedIndex::<WideCompressedSeq, Hash<_>>::load(filename)
+            .unwrap_err()
+            .to_string();
+        assert!(err.ends_with("keys hold 32 bases, not 64"), "{err}");
+
+        // Cut short
+        fs::write(filename, &bytes[..bytes.len() - 3]).unwrap();
+        let err = SavedIndex::<u64, Hash>::load(filename)
+            .unwrap_err()
+            .to_string();
+        assert!(err.ends_with("index is truncated"), "{err}");
+    }
+}
@@ -0,0 +1,25 @@
+//! Counts the reads that hit each of a panel of junction sequences.
+//!
+//! A [`Counter`] is set up with a [`CounterBuilder`], from a junctions
+//! file, junctions in memory or an index saved by an earlier counter,
+//! and then counts the hits in reads fed to it or in whole reads files.
+//! The modules it is built on are public for finer control.
+
+pub mod bloom;
+#[cfg(feature = "columnar")]
+pub mod columnar;
+pub mod compress;
+pub mod constants;
+pub mod counter;
+pub mod counts;
+pub mod error;
+pub mod hash;
+pub mod index;
+pub mod output;
+pub mod panel;
+pub mod perfect;
+pub mod search;
+pub mod summary;
+
+pub use counter::{Counter, CounterBuilder, Feed, Stats, Tallies, Tally};
+pub use error::{Error, Result};
@@ -1,27 +1,143 @@
-use crate::{compress::compress_seq, search::Search};
 use anyhow::{anyhow, bail, Result};
-use clap::Parser;
-use kseq::parse_path;
+use clap::{Parser, Subcommand};
+use itertools::Itertools;
 use rayon::prelude::*;
 use std::{
-    collections::HashMap,
     fs::{self, File},
-    io::Write,
+    io::{self, BufWriter, Write},
     path::Path,
     time::Instant,
 };
-
-pub mod compress;
-pub mod constants;
-pub mod hash;
-pub mod search;
+use tallyman::{
+    compress::MAX_KMER_SIZE,
+    index::Backend,
+    output::{
+        write_matrix, Column, Format, MatrixFormat, SampleOutput, Selection,
+        Sort,
+    },
+    panel::Ambiguous,
+    search::Strand,
+    summary::{merge_counts, read_stats},
+    Counter, CounterBuilder,
+};
 
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
-struct Args {
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Threads
+    #[arg(short, long, value_name = "THREADS", global = true)]
+    threads: Option<usize>,
+
+    /// Verbose output
+    #[arg(long, short, global = true)]
+    verbose: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Count the hits on each junction in reads files
+    Count(CountArgs),
+    /// Index a junctions file once to count many reads files against
+    Index(IndexArgs),
+    /// Check that a junctions file or index can be counted against
+    Validate(ValidateArgs),
+    /// Add up the counts from runs on pieces of the same sample
+    Merge(MergeArgs),
+    /// Summarize the reads and junctions hit in a directory of counts
+    Stats(StatsArgs),
+}
+
+/// Where the junctions come from.
+#[derive(Debug, clap::Args)]
+#[group(required = true, multiple = false)]
+struct JunctionSource {
     /// Junctions file
     #[arg(short, long, value_name = "JUNCTIONS")]
-    junctions: String,
+    junctions: Option<String>,
+
+    /// Junction index written by "tallyman index", read instead of a
+    /// junctions file along with the options it was built with
+    #[arg(
+        long,
+        value_name = "INDEX",
+        conflicts_with_all = [
+            "kmer_size",
+            "strand",
+            "ambiguous",
+            "max_expansions",
+            "max_mismatches",
+            "backend",
+        ]
+    )]
+    index: Option<String>,
+}
+
+/// How the junctions are read and looked up.
+#[derive(Debug, clap::Args)]
+struct JunctionArgs {
+    /// Length of the junction k-mers, leaving out junctions of other
+    /// lengths [default: each junction's own length]
+    #[arg(short, long, value_name = "KMER_SIZE", value_parser = parse_kmer_size)]
+    kmer_size: Option<usize>,
+
+    /// Strand(s) of the reads to search
+    #[arg(short, long, value_name = "STRAND", value_enum, default_value_t)]
+    strand: Strand,
+
+    /// What to do with junctions containing IUPAC ambiguity codes
+    #[arg(long, value_name = "POLICY", value_enum, default_value_t)]
+    ambiguous: Ambiguous,
+
+    /// Most k-mers an ambiguous junction may expand to
+    #[arg(long, value_name = "MAX_EXPANSIONS", default_value = "256")]
+    max_expansions: usize,
+
+    /// Also count hits within this many mismatches, reported separately
+    #[arg(
+        short,
+        long,
+        value_name = "MISMATCHES",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=2)
+    )]
+    max_mismatches: u8,
+
+    /// Treat U as an invalid base instead of reading it as T
+    #[arg(long)]
+    no_uracil: bool,
+
+    /// How the junction k-mers are looked up
+    #[arg(long, value_name = "BACKEND", value_enum, default_value_t)]
+    backend: Backend,
+}
+
+impl JunctionArgs {
+    /// A counter set up to read the junctions as asked.
+    fn builder(&self) -> CounterBuilder {
+        let builder = Counter::builder()
+            .strand(self.strand)
+            .ambiguous(self.ambiguous)
+            .max_expansions(self.max_expansions)
+            .max_mismatches(self.max_mismatches)
+            .uracil(!self.no_uracil)
+            .backend(self.backend);
+        match self.kmer_size {
+            Some(kmer_size) => builder.kmer_size(kmer_size),
+            _ => builder,
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct CountArgs {
+    #[command(flatten)]
+    source: JunctionSource,
+
+    #[command(flatten)]
+    junction: JunctionArgs,
 
     /// Reads file(s)
     #[arg(short, long, value_name = "READS", num_args(1..), required(true))]
@@ -31,133 +147,333 @@ struct Args {
     #[arg(short, long, value_name = "OUTDIR", default_value = "out")]
     outdir: String,
 
-    /// Threads
-    #[arg(short, long, value_name = "THREADS")]
-    threads: Option<usize>,
+    /// Count up to 2^64 - 1 hits per k-mer instead of 2^32 - 1
+    #[arg(long)]
+    wide_counts: bool,
 
-    /// Verbose output
-    #[arg(long, short)]
-    verbose: bool,
+    /// Check each k-mer against a Bloom filter before looking it up
+    #[arg(long)]
+    prefilter: bool,
+
+    /// False positive rate the prefilter is sized for
+    #[arg(
+        long,
+        value_name = "RATE",
+        default_value = "0.01",
+        value_parser = parse_fp_rate
+    )]
+    prefilter_fp_rate: f64,
+
+    /// Have all threads count into one table of atomic counters rather
+    /// than each keeping its own
+    #[arg(long)]
+    shared_counts: bool,
+
+    /// How the hits in each reads file are written
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t)]
+    format: Format,
+
+    /// Also write the hits on every junction in every reads file to one
+    /// TSV, a row per junction and a column per reads file
+    #[arg(long, value_name = "MATRIX")]
+    matrix: Option<String>,
+
+    /// How the matrix is written
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t,
+        requires = "matrix"
+    )]
+    matrix_format: MatrixFormat,
+
+    /// The order junctions are written in
+    #[arg(long, value_name = "SORT", value_enum, default_value_t)]
+    sort: Sort,
+
+    /// Write every junction, including those with no hits
+    #[arg(long, conflicts_with = "min_count")]
+    report_zeros: bool,
+
+    /// Only write junctions with at least this many hits, counting
+    /// mismatch hits when mismatches are allowed; in a matrix, those
+    /// with this many in some reads file
+    #[arg(long, value_name = "MIN_COUNT")]
+    min_count: Option<u64>,
+}
+
+#[derive(Debug, clap::Args)]
+struct IndexArgs {
+    /// Junctions file
+    #[arg(short, long, value_name = "JUNCTIONS")]
+    junctions: String,
+
+    /// Index file to write
+    #[arg(short, long, value_name = "INDEX")]
+    output: String,
+
+    #[command(flatten)]
+    junction: JunctionArgs,
+}
+
+#[derive(Debug, clap::Args)]
+struct ValidateArgs {
+    #[command(flatten)]
+    source: JunctionSource,
+
+    #[command(flatten)]
+    junction: JunctionArgs,
+}
+
+#[derive(Debug, clap::Args)]
+struct MergeArgs {
+    /// Count output files of the same sample, all in one text format
+    #[arg(value_name = "FILES", num_args(1..), required(true))]
+    files: Vec<String>,
+
+    /// Output file [default: STDOUT]
+    #[arg(short, long, value_name = "OUTPUT")]
+    output: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct StatsArgs {
+    /// Output directory of a count
+    #[arg(value_name = "OUTDIR", default_value = "out")]
+    outdir: String,
 }
 
 // --------------------------------------------------
 fn main() {
-    if let Err(e) = run(Args::parse()) {
+    if let Err(e) = run(Cli::parse()) {
         eprintln!("{e}");
         std::process::exit(1);
     }
 }
 
 // --------------------------------------------------
-fn run(args: Args) -> Result<()> {
+fn run(cli: Cli) -> Result<()> {
     // Optionally set num of threads, default will use all available
-    if let Some(num) = args.threads {
+    if let Some(num) = cli.threads {
         rayon::ThreadPoolBuilder::new()
             .num_threads(num)
             .build_global()
             .unwrap();
     }
 
-    // Load the DCE sequences and compress them,
-    // make the multimap for post-processing
-    let timer = Instant::now();
-    let mut map = HashMap::new();
-    let mut junctions = vec![];
-    let mut junctions_file = get_reader(&args.junctions)?;
-
-    while let Some(rec) = junctions_file.iter_record()? {
-        match compress_seq(rec.seq()) {
-            Some(comp) => {
-                junctions.push(comp);
-                if map.get(&comp).is_some() {
-                    eprintln!(
-                        r#"WARNING: Junction sequence "{}" ({}) duplicated"#,
-                        rec.seq(),
-                        rec.head()
-                    );
-                } else {
-                    map.insert(comp, rec.head().to_string());
-                }
-            }
-            _ => eprintln!(
-                r#"Junction sequence "{}" ({}) rejected"#,
-                rec.seq(),
-                rec.head()
-            ),
-        }
+    match cli.command {
+        Command::Count(args) => count_reads(args, cli.verbose),
+        Command::Index(args) => index(args, cli.verbose),
+        Command::Validate(args) => validate(args),
+        Command::Merge(args) => merge(args),
+        Command::Stats(args) => stats(args),
     }
+}
+
+// --------------------------------------------------
+/// Reads the junctions, or loads the index they were saved in, and
+/// warns about any that were left out or duplicated.
+fn load(source: &JunctionSource, builder: CounterBuilder) -> Result<Counter> {
+    let counter = match (&source.index, &source.junctions) {
+        (Some(filename), _) => builder.index_file(filename)?,
+        (_, Some(filename)) => builder.junctions_file(filename)?,
+        _ => bail!("No junctions"),
+    };
 
-    if args.verbose {
+    for (junction, e) in counter.rejected() {
         eprintln!(
-            "Time to load and hash DCE sequences: {:?}",
-            timer.elapsed()
+            r#"Junction sequence "{}" ({}) rejected: {e}"#,
+            junction.seq, junction.name
         );
     }
+    for junction in counter.duplicated() {
+        eprintln!(
+            r#"WARNING: Junction sequence "{}" ({}) duplicated"#,
+            junction.seq, junction.name
+        );
+    }
+    Ok(counter)
+}
 
-    if junctions.is_empty() {
-        bail!("No junctions");
+// --------------------------------------------------
+fn count_reads(args: CountArgs, verbose: bool) -> Result<()> {
+    let timer = Instant::now();
+    let mut builder = args
+        .junction
+        .builder()
+        .wide_counts(args.wide_counts)
+        .shared_counts(args.shared_counts);
+    if args.prefilter {
+        builder = builder.prefilter(args.prefilter_fp_rate);
+    }
+    let counter = load(&args.source, builder)?;
+    if verbose {
+        eprintln!("Time to load junctions: {:?}", timer.elapsed());
     }
 
+    let settings = counter.settings();
+    let kmer_size = settings.kmer_sizes[0];
     let outdir = Path::new(&args.outdir);
 
     if !outdir.exists() {
-        fs::create_dir_all(&outdir)?;
+        fs::create_dir_all(outdir)?;
     }
 
-    args.reads
-        .into_par_iter()
-        .try_for_each(|reads_file| -> Result<()> {
-            let basename = Path::new(&reads_file)
-                .file_name()
-                .ok_or(anyhow!("basename"))?
-                .to_os_string();
-
-            let mut out_data_file = basename.clone();
-            out_data_file.push(".txt");
-            let out_data_path = &outdir.join(out_data_file);
-            let mut out_data = File::create(out_data_path)?;
-
-            let mut out_count_file = basename.clone();
-            out_count_file.push(".count");
-            let out_count_path = &outdir.join(out_count_file);
-            let mut out_count = File::create(out_count_path)?;
-
-            // Search through each of the RNA sequences, reusing
-            // the sequence and search results instances.
+    let selection = Selection {
+        sort: args.sort,
+        report_zeros: args.report_zeros,
+        min_count: args.min_count,
+    };
+    let columns = args
+        .reads
+        .par_iter()
+        .map(|reads_file| -> Result<Option<Column>> {
+            let output = SampleOutput::create(outdir, reads_file, args.format)?;
             let timer = Instant::now();
-            let mut reads: kseq::Paths = get_reader(&reads_file)?;
-            writeln!(out_data, "File: {}", &reads_file)?;
-
-            let mut search: Search = Search::new(&junctions)?;
-            let mut read_count = 0;
-            while let Some(rec) = reads.iter_record()? {
-                search.search(rec.seq());
-                read_count += 1;
-            }
 
-            if args.verbose {
+            let tallies = counter.count_file(reads_file)?;
+            let stats = tallies.stats;
+
+            if verbose {
                 eprintln!(
                     r#"Time to search "{reads_file}": {:?}"#,
                     timer.elapsed()
                 );
-            }
-
-            for (i, count) in search.junctions.hits.into_iter().enumerate() {
-                if count > 0 {
-                    if let Some(name) = map.get(&search.junctions.key[i]) {
-                        writeln!(out_data, "{name}\t{count}")?;
-                    }
+                eprintln!(
+                    r#"Skipped {} of {} reads shorter than {kmer_size} in "{reads_file}""#,
+                    stats.short_reads, stats.reads
+                );
+                if args.prefilter {
+                    eprintln!(
+                        r#"Prefilter rejected {} of {} k-mers ({:.2}%) in "{reads_file}""#,
+                        stats.rejected,
+                        stats.lookups,
+                        100.0 * stats.rejected as f64
+                            / stats.lookups.max(1) as f64
+                    );
                 }
             }
 
-            writeln!(out_count, "{read_count}")?;
-            Ok(())
-        })?;
+            // Only what the matrix needs is kept once the file is done
+            let column = args
+                .matrix
+                .as_ref()
+                .map(|_| Column::new(&output.sample, &tallies));
+            output.write(selection, &counter, &tallies, args.prefilter)?;
+            Ok(column)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if let Some(filename) = &args.matrix {
+        let columns: Vec<Column> = columns.into_iter().flatten().collect();
+        let mut out = BufWriter::new(
+            File::create(filename).map_err(|e| anyhow!("{filename}: {e}"))?,
+        );
+        write_matrix(
+            &mut out,
+            args.matrix_format,
+            selection,
+            &counter,
+            &columns,
+        )?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+/// Indexes a junctions file and saves it, with everything a count
+/// needs, to be loaded instead of reading the junctions again.
+fn index(args: IndexArgs, verbose: bool) -> Result<()> {
+    let timer = Instant::now();
+    let source = JunctionSource {
+        junctions: Some(args.junctions),
+        index: None,
+    };
+    let counter = load(&source, args.junction.builder())?;
+    counter.save(&args.output)?;
 
+    if verbose {
+        eprintln!(
+            r#"Time to index {} junctions into "{}": {:?}"#,
+            counter.names().len(),
+            args.output,
+            timer.elapsed()
+        );
+    }
     Ok(())
 }
 
 // --------------------------------------------------
-fn get_reader(filename: &str) -> Result<kseq::Paths> {
-    parse_path(filename).map_err(|e| anyhow!("{filename}: {e}"))
+/// Reads a junctions file or loads an index as a count would, and
+/// reports what would be counted. Fails if any junction is rejected.
+fn validate(args: ValidateArgs) -> Result<()> {
+    let counter = load(&args.source, args.junction.builder())?;
+    let kmer_sizes = &counter.settings().kmer_sizes;
+    println!("kmer_size\t{}", kmer_sizes.iter().join(","));
+    println!("junctions\t{}", counter.names().len());
+    println!("kmers\t{}", counter.kmers());
+
+    // Only a junctions file has anything left out to report
+    if args.source.junctions.is_some() {
+        let rejected = counter.rejected().len();
+        println!("rejected\t{rejected}");
+        println!("duplicated\t{}", counter.duplicated().len());
+
+        if rejected > 0 {
+            bail!(
+                "{rejected} of {} junctions rejected",
+                rejected + counter.names().len()
+            );
+        }
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+/// Adds up the hits on each junction in the output of several counts,
+/// such as those of the lanes of one sample.
+fn merge(args: MergeArgs) -> Result<()> {
+    let merged = merge_counts(&args.files)?;
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(filename) => Box::new(
+            File::create(filename).map_err(|e| anyhow!("{filename}: {e}"))?,
+        ),
+        _ => Box::new(io::stdout()),
+    };
+    merged.write(&mut out)?;
+    Ok(())
+}
+
+// --------------------------------------------------
+/// Lists the reads and the junctions hit for every reads file counted
+/// into a directory.
+fn stats(args: StatsArgs) -> Result<()> {
+    println!("file\treads\tshort_reads\tjunctions");
+    for sample in read_stats(Path::new(&args.outdir))? {
+        println!(
+            "{}\t{}\t{}\t{}",
+            sample.sample, sample.reads, sample.short_reads, sample.junctions
+        );
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+fn parse_kmer_size(val: &str) -> Result<usize> {
+    match val.parse() {
+        Ok(k) if (1..=MAX_KMER_SIZE).contains(&k) => Ok(k),
+        _ => bail!(
+            r#"kmer size "{val}" must be between 1 and {MAX_KMER_SIZE}"#
+        ),
+    }
+}
+
+// --------------------------------------------------
+fn parse_fp_rate(val: &str) -> Result<f64> {
+    match val.parse() {
+        Ok(rate) if rate > 0.0 && rate < 1.0 => Ok(rate),
+        _ => bail!(r#"false positive rate "{val}" must be between 0 and 1"#),
+    }
 }
@@ -0,0 +1,660 @@
+#[cfg(feature = "columnar")]
+use crate::columnar::{Columnar, CountTable};
+use crate::{
+    counter::{Counter, Stats, Tallies, Tally},
+    error::{Error, OutputError, Result},
+    search::Strand,
+};
+use clap::ValueEnum;
+use std::{
+    cmp::Reverse,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// How the hits in each reads file are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// A "File:" line, then a junction name and its hits on each line
+    #[default]
+    Legacy,
+    /// Tab-separated values with a header
+    Tsv,
+    /// Comma-separated values with a header
+    Csv,
+    /// A JSON object on each line
+    Jsonl,
+    /// A Parquet table with the fields of the other tables
+    #[cfg(feature = "columnar")]
+    Parquet,
+    /// An Arrow IPC file with the fields of the other tables
+    #[cfg(feature = "columnar")]
+    Arrow,
+}
+
+impl Format {
+    /// The extension of the files written in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Legacy => "txt",
+            Format::Tsv => "tsv",
+            Format::Csv => "csv",
+            Format::Jsonl => "jsonl",
+            #[cfg(feature = "columnar")]
+            Format::Parquet => "parquet",
+            #[cfg(feature = "columnar")]
+            Format::Arrow => "arrow",
+        }
+    }
+
+    /// The columnar format this is, if it is not text.
+    #[cfg(feature = "columnar")]
+    pub(crate) fn columnar(self) -> Option<Columnar> {
+        match self {
+            Format::Parquet => Some(Columnar::Parquet),
+            Format::Arrow => Some(Columnar::Arrow),
+            _ => None,
+        }
+    }
+}
+
+/// How the hits in every reads file are written together.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum MatrixFormat {
+    /// A TSV with a row per junction and a column per reads file
+    #[default]
+    Tsv,
+    /// A Parquet table with a row per junction per reads file
+    #[cfg(feature = "columnar")]
+    Parquet,
+    /// An Arrow IPC file with a row per junction per reads file
+    #[cfg(feature = "columnar")]
+    Arrow,
+}
+
+impl MatrixFormat {
+    /// The columnar format this is, if it is not text.
+    #[cfg(feature = "columnar")]
+    fn columnar(self) -> Option<Columnar> {
+        match self {
+            MatrixFormat::Tsv => None,
+            MatrixFormat::Parquet => Some(Columnar::Parquet),
+            MatrixFormat::Arrow => Some(Columnar::Arrow),
+        }
+    }
+}
+
+/// The order junctions are written in. Ties keep the order of the
+/// junctions file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Sort {
+    /// The order of the junctions file
+    #[default]
+    Input,
+    /// By name
+    Name,
+    /// Most exact hits first, then most mismatch hits
+    Count,
+}
+
+impl Sort {
+    /// Sorts the junctions at `rows`, which are in input order, given
+    /// the exact and mismatch hits on each.
+    fn apply(
+        self,
+        rows: &mut [usize],
+        names: &[String],
+        hits: impl Fn(usize) -> (u64, u64),
+    ) {
+        match self {
+            Sort::Input => {}
+            Sort::Name => rows.sort_by(|a, b| names[*a].cmp(&names[*b])),
+            Sort::Count => rows.sort_by_key(|index| Reverse(hits(*index))),
+        }
+    }
+}
+
+/// The fields of every table format, in order. The mismatch hits are
+/// those within the allowed mismatches but not exact.
+pub(crate) const FIELDS: [&str; 10] = [
+    "sample",
+    "junction",
+    "sequence",
+    "forward",
+    "reverse",
+    "total",
+    "mismatch_forward",
+    "mismatch_reverse",
+    "mismatch_total",
+    "reads",
+];
+
+/// Which junctions are written, and in what order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Selection {
+    pub sort: Sort,
+    /// Write every junction, hit or not
+    pub report_zeros: bool,
+    /// The fewest hits a junction may have and be written, counting
+    /// mismatch hits when mismatches are allowed. A sample's counts
+    /// take this to be 1 when it is not given, and a matrix 0.
+    pub min_count: Option<u64>,
+}
+
+impl Selection {
+    /// The fewest hits on a junction written for one sample.
+    fn sample_min_count(self) -> u64 {
+        if self.report_zeros {
+            0
+        } else {
+            self.min_count.unwrap_or(1)
+        }
+    }
+
+    /// The fewest hits in some sample on a junction in a matrix.
+    fn matrix_min_count(self) -> u64 {
+        if self.report_zeros {
+            0
+        } else {
+            self.min_count.unwrap_or(0)
+        }
+    }
+}
+
+/// The exact and mismatch hits on a junction, on every strand.
+fn hits(tally: Tally) -> (u64, u64) {
+    let (forward, reverse) = tally.exact;
+    let (approx_forward, approx_reverse) = tally.approx;
+    (forward + reverse, approx_forward + approx_reverse)
+}
+
+/// The hits held up against a minimum count.
+fn counted((exact, approx): (u64, u64), max_mismatches: u8) -> u64 {
+    if max_mismatches > 0 {
+        exact + approx
+    } else {
+        exact
+    }
+}
+
+/// Writes the hits a counter found in `reads_file`, which is known as
+/// `sample` in the table formats. Only the junctions `selection` keeps
+/// are written, in its order.
+pub fn write_counts(
+    out: &mut (impl Write + Send),
+    format: Format,
+    selection: Selection,
+    reads_file: &str,
+    sample: &str,
+    counter: &Counter,
+    tallies: &Tallies,
+) -> io::Result<()> {
+    let settings = counter.settings();
+    let min_count = selection.sample_min_count();
+    let mut rows: Vec<usize> = (0..tallies.tallies.len())
+        .filter(|index| {
+            let hits = hits(tallies.tallies[*index]);
+            counted(hits, settings.max_mismatches) >= min_count
+        })
+        .collect();
+    selection.sort.apply(&mut rows, counter.names(), |index| {
+        hits(tallies.tallies[index])
+    });
+
+    #[cfg(feature = "columnar")]
+    if let Some(columnar) = format.columnar() {
+        let mut table = CountTable::default();
+        for index in rows {
+            table.add(
+                sample,
+                &counter.names()[index],
+                &counter.seqs()[index],
+                tallies.tallies[index],
+                tallies.stats.reads,
+            );
+        }
+        return table.write(out, columnar);
+    }
+
+    match format {
+        Format::Legacy => writeln!(out, "File: {reads_file}")?,
+        Format::Tsv => writeln!(out, "{}", FIELDS.join("\t"))?,
+        Format::Csv => writeln!(out, "{}", FIELDS.join(","))?,
+        _ => {}
+    }
+
+    for index in rows {
+        let tally = tallies.tallies[index];
+        let name = &counter.names()[index];
+        let seq = &counter.seqs()[index];
+        match format {
+            Format::Legacy => {
+                // Approximate hits follow the exact ones
+                let mut all_hits = vec![tally.exact];
+                if settings.max_mismatches > 0 {
+                    all_hits.push(tally.approx);
+                }
+
+                // Only a forward search keeps the plain two columns
+                write!(out, "{name}")?;
+                for (forward, reverse) in all_hits {
+                    if settings.strand == Strand::Forward {
+                        write!(out, "\t{forward}")?;
+                    } else {
+                        let total = forward + reverse;
+                        write!(out, "\t{forward}\t{reverse}\t{total}")?;
+                    }
+                }
+                writeln!(out)?;
+            }
+            Format::Tsv => {
+                let counts = text_counts(tally, tallies.stats.reads);
+                writeln!(
+                    out,
+                    "{sample}\t{name}\t{seq}\t{}",
+                    counts.join("\t")
+                )?;
+            }
+            Format::Csv => {
+                let counts = text_counts(tally, tallies.stats.reads);
+                writeln!(
+                    out,
+                    "{},{},{},{}",
+                    csv_field(sample),
+                    csv_field(name),
+                    csv_field(seq),
+                    counts.join(",")
+                )?;
+            }
+            Format::Jsonl => {
+                let strings = [sample, name, seq].map(json_string);
+                let counts = text_counts(tally, tallies.stats.reads);
+                let fields: Vec<String> = FIELDS
+                    .iter()
+                    .zip(strings.iter().chain(&counts))
+                    .map(|(field, value)| format!(r#""{field}":{value}"#))
+                    .collect();
+                writeln!(out, "{{{}}}", fields.join(","))?;
+            }
+            #[cfg(feature = "columnar")]
+            Format::Parquet | Format::Arrow => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+/// Writes what became of the reads searched, a stat on each line.
+/// The prefilter's lookups are only written if it was used.
+pub fn write_stats(
+    out: &mut impl Write,
+    stats: Stats,
+    prefilter: bool,
+) -> io::Result<()> {
+    writeln!(out, "reads\t{}", stats.reads)?;
+    writeln!(out, "short_reads\t{}", stats.short_reads)?;
+    if prefilter {
+        writeln!(out, "prefilter_lookups\t{}", stats.lookups)?;
+        writeln!(out, "prefilter_rejected\t{}", stats.rejected)?;
+    }
+    Ok(())
+}
+
+/// The files a count writes into an output directory for one reads
+/// file, each named after it: the hits (`<sample>.txt` or the
+/// extension of the format), the number of reads (`<sample>.count`)
+/// and the stats (`<sample>.stats`).
+pub struct SampleOutput {
+    /// The file name of the reads file
+    pub sample: String,
+    reads_file: String,
+    format: Format,
+    hits: OutputFile,
+    reads: OutputFile,
+    stats: OutputFile,
+}
+
+impl SampleOutput {
+    /// Creates the files for `reads_file` in `outdir`, before it is
+    /// counted.
+    pub fn create(
+        outdir: &Path,
+        reads_file: &str,
+        format: Format,
+    ) -> Result<SampleOutput> {
+        let sample = Path::new(reads_file)
+            .file_name()
+            .ok_or_else(|| Error::Output {
+                path: reads_file.to_string(),
+                source: OutputError::FileName,
+            })?
+            .to_string_lossy()
+            .to_string();
+        let create = |extension: &str| {
+            OutputFile::create(outdir.join(format!("{sample}.{extension}")))
+        };
+
+        Ok(SampleOutput {
+            hits: create(format.extension())?,
+            reads: create("count")?,
+            stats: create("stats")?,
+            sample,
+            reads_file: reads_file.to_string(),
+            format,
+        })
+    }
+
+    /// Writes the hits a counter found in the reads file, the number of
+    /// reads and their stats.
+    pub fn write(
+        mut self,
+        selection: Selection,
+        counter: &Counter,
+        tallies: &Tallies,
+        prefilter: bool,
+    ) -> Result<()> {
+        let stats = tallies.stats;
+        self.hits.write(|out| {
+            write_counts(
+                out,
+                self.format,
+                selection,
+                &self.reads_file,
+                &self.sample,
+                counter,
+                tallies,
+            )
+        })?;
+        self.reads.write(|out| writeln!(out, "{}", stats.reads))?;
+        self.stats.write(|out| write_stats(out, stats, prefilter))
+    }
+}
+
+/// A file being written, with its path to report errors against.
+struct OutputFile {
+    path: PathBuf,
+    out: BufWriter<File>,
+}
+
+impl OutputFile {
+    fn create(path: PathBuf) -> Result<OutputFile> {
+        match File::create(&path) {
+            Ok(file) => Ok(OutputFile {
+                path,
+                out: BufWriter::new(file),
+            }),
+            Err(source) => Err(Error::Io {
+                path: path.display().to_string(),
+                source,
+            }),
+        }
+    }
+
+    /// Writes the whole file with `write`.
+    fn write(
+        &mut self,
+        write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+    ) -> Result<()> {
+        write(&mut self.out)
+            .and_then(|()| self.out.flush())
+            .map_err(|source| Error::Io {
+                path: self.path.display().to_string(),
+                source,
+            })
+    }
+}
+
+/// The counts of a row of a table, from the forward hits to the reads.
+pub(crate) fn counts(tally: Tally, reads: u64) -> [u64; 7] {
+    let (forward, reverse) = tally.exact;
+    let (approx_forward, approx_reverse) = tally.approx;
+    [
+        forward,
+        reverse,
+        forward + reverse,
+        approx_forward,
+        approx_reverse,
+        approx_forward + approx_reverse,
+        reads,
+    ]
+}
+
+/// The counts of a row of a text table.
+fn text_counts(tally: Tally, reads: u64) -> [String; 7] {
+    counts(tally, reads).map(|count| count.to_string())
+}
+
+/// Quotes a CSV field if it holds anything that would split it.
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// A JSON string holding `value`.
+pub(crate) fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for chr in value.chars() {
+        match chr {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            chr if chr < ' ' => {
+                json.push_str(&format!("\\u{:04x}", chr as u32))
+            }
+            chr => json.push(chr),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// One sample's hits on every junction, kept to be written with the
+/// others in a junction-by-sample matrix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub sample: String,
+    pub tallies: Vec<Tally>,
+    pub reads: u64,
+}
+
+impl Column {
+    pub fn new(sample: &str, tallies: &Tallies) -> Column {
+        Column {
+            sample: sample.to_string(),
+            tallies: tallies.tallies.clone(),
+            reads: tallies.stats.reads,
+        }
+    }
+
+    /// The hits on a junction on every strand searched, those within
+    /// the mismatches allowed included, as held up to a minimum count.
+    pub fn hits(&self, index: usize, max_mismatches: u8) -> u64 {
+        counted(hits(self.tallies[index]), max_mismatches)
+    }
+}
+
+/// Writes the hits on every junction in every sample together, sorting
+/// by the hits in all of them. The TSV has a row per junction, zeros
+/// included unless `selection` has a minimum count for some sample to
+/// reach, and a column per sample, with the reads in each sample in a
+/// last row. The columnar formats have a row per junction per sample,
+/// with the fields of `write_counts`.
+#[cfg_attr(not(feature = "columnar"), allow(unused_variables))]
+pub fn write_matrix(
+    out: &mut (impl Write + Send),
+    format: MatrixFormat,
+    selection: Selection,
+    counter: &Counter,
+    columns: &[Column],
+) -> io::Result<()> {
+    let names = counter.names();
+    let max_mismatches = counter.settings().max_mismatches;
+    let min_count = selection.matrix_min_count();
+    let mut rows: Vec<usize> = (0..names.len())
+        .filter(|index| {
+            min_count == 0
+                || columns.iter().any(|column| {
+                    column.hits(*index, max_mismatches) >= min_count
+                })
+        })
+        .collect();
+    selection.sort.apply(&mut rows, names, |index| {
+        columns.iter().fold((0, 0), |(exact, approx), column| {
+            let hits = hits(column.tallies[index]);
+            (exact + hits.0, approx + hits.1)
+        })
+    });
+
+    #[cfg(feature = "columnar")]
+    if let Some(columnar) = format.columnar() {
+        let seqs = counter.seqs();
+        let mut table = CountTable::default();
+        for column in columns {
+            for &index in &rows {
+                table.add(
+                    &column.sample,
+                    &names[index],
+                    &seqs[index],
+                    column.tallies[index],
+                    column.reads,
+                );
+            }
+        }
+        return table.write(out, columnar);
+    }
+
+    write!(out, "junction")?;
+    for column in columns {
+        write!(out, "\t{}", column.sample)?;
+    }
+    writeln!(out)?;
+
+    for &index in &rows {
+        write!(out, "{}", names[index])?;
+        for column in columns {
+            write!(out, "\t{}", column.hits(index, max_mismatches))?;
+        }
+        writeln!(out)?;
+    }
+
+    write!(out, "total_reads")?;
+    for column in columns {
+        write!(out, "\t{}", column.reads)?;
+    }
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        counter::{Counter, Stats, Tallies, Tally},
+        output::{
+            csv_field, json_string, write_matrix, Column, MatrixFormat,
+            Selection, Sort,
+        },
+    };
+
+    #[test]
+    fn escaping() {
+        assert_eq!(csv_field("polyC"), "polyC");
+        assert_eq!(csv_field("chr1, exon 2"), r#""chr1, exon 2""#);
+        assert_eq!(csv_field(r#"say "hi""#), r#""say ""hi""""#);
+
+        assert_eq!(json_string("polyC"), r#""polyC""#);
+        assert_eq!(json_string("a\"b\\c\td\u{1}"), r#""a\"b\\c\td\u0001""#);
+        assert_eq!(json_string("naïve"), r#""naïve""#);
+    }
+
+    #[test]
+    fn matrix() {
+        let tallies = Tallies {
+            tallies: vec![
+                Tally {
+                    exact: (3, 1),
+                    approx: (7, 0),
+                },
+                Tally::default(),
+            ],
+            stats: Stats {
+                reads: 10,
+                ..Stats::default()
+            },
+        };
+        let mut empty = tallies.clone();
+        empty.tallies.fill(Tally::default());
+        empty.stats.reads = 0;
+        let columns =
+            [Column::new("a.fa", &tallies), Column::new("b.fa", &empty)];
+        assert_eq!(columns[0].hits(0, 0), 4);
+        assert_eq!(columns[0].hits(0, 1), 11);
+
+        let counter = Counter::builder()
+            .junctions([("one", "ACGT"), ("two", "TTTT")])
+            .unwrap();
+        let matrix = |selection| {
+            let mut out = vec![];
+            write_matrix(
+                &mut out,
+                MatrixFormat::Tsv,
+                selection,
+                &counter,
+                &columns,
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            matrix(Selection::default()),
+            "junction\ta.fa\tb.fa\n\
+            one\t4\t0\n\
+            two\t0\t0\n\
+            total_reads\t10\t0\n"
+        );
+
+        // A minimum count leaves out the junctions no sample reaches it
+        // on, and without mismatches allowed only exact hits count
+        for (min_count, rows) in [(1, "one\t4\t0\n"), (5, "")] {
+            let selection = Selection {
+                sort: Sort::Name,
+                min_count: Some(min_count),
+                ..Selection::default()
+            };
+            assert_eq!(
+                matrix(selection),
+                format!("junction\ta.fa\tb.fa\n{rows}total_reads\t10\t0\n")
+            );
+        }
+
+        // With mismatches allowed the cells count the mismatch hits too,
+        // just as the minimum count does
+        let counter = Counter::builder()
+            .max_mismatches(1)
+            .junctions([("one", "ACGT"), ("two", "TTTT")])
+            .unwrap();
+        for (min_count, rows) in [(5, "one\t11\t0\n"), (12, "")] {
+            let selection = Selection {
+                min_count: Some(min_count),
+                ..Selection::default()
+            };
+            let mut out = vec![];
+            write_matrix(
+                &mut out,
+                MatrixFormat::Tsv,
+                selection,
+                &counter,
+                &columns,
+            )
+            .unwrap();
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                format!("junction\ta.fa\tb.fa\n{rows}total_reads\t10\t0\n")
+            );
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use crate::{
+    compress::{neighbors, Alphabet, CompressedSeq, Kmer, WideCompressedSeq},
+    error::{Error, Rejection, Result},
+    index::Credit,
+    search::Strand,
+};
+use clap::ValueEnum;
+use kseq::parse_path;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Ambiguous {
+    /// Reject the junction
+    #[default]
+    Reject,
+    /// Count every concrete k-mer the junction could stand for
+    Expand,
+}
+
+/// How the junctions of a panel are turned into k-mers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PanelOptions {
+    pub alphabet: Alphabet,
+    /// The length of every junction k-mer, or `None` to match each
+    /// junction at its own length
+    pub kmer_size: Option<usize>,
+    pub strand: Strand,
+    pub ambiguous: Ambiguous,
+    pub max_expansions: usize,
+    pub max_mismatches: u8,
+}
+
+/// A junction just as it was given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Junction {
+    pub name: String,
+    pub seq: String,
+}
+
+/// The k-mers of the junctions of one length, to be indexed together,
+/// and the junctions credited with the hits on each of them.
+#[derive(Debug)]
+pub struct Group<K: Kmer> {
+    pub kmer_size: usize,
+    pub credits: HashMap<K, Vec<Credit>>,
+    pub junctions: Vec<K>,
+}
+
+/// The junctions to count: their names, and a group of k-mers for each
+/// length of junction.
+#[derive(Debug)]
+pub struct Panel<K: Kmer> {
+    pub options: PanelOptions,
+    pub names: Vec<String>,
+    /// The sequence of each junction, as given
+    pub seqs: Vec<String>,
+    /// The groups, shortest k-mers first
+    pub groups: Vec<Group<K>>,
+    /// Junctions left out, and why
+    pub rejected: Vec<(Junction, Rejection)>,
+    /// Junctions sharing a k-mer with one added before them
+    pub duplicated: Vec<Junction>,
+}
+
+impl<K: Kmer> Panel<K> {
+    pub fn new(options: PanelOptions) -> Panel<K> {
+        Panel {
+            options,
+            names: vec![],
+            seqs: vec![],
+            groups: vec![],
+            rejected: vec![],
+            duplicated: vec![],
+        }
+    }
+
+    /// Reads every junction in `filename`.
+    pub fn read(filename: &str, options: PanelOptions) -> Result<Panel<K>> {
+        let mut panel = Panel::new(options);
+        let mut junctions_file = get_reader(filename)?;
+        while let Some(rec) =
+            junctions_file
+                .iter_record()
+                .map_err(|source| Error::Parse {
+                    path: filename.to_string(),
+                    source,
+                })?
+        {
+            panel.add(rec.head(), rec.seq());
+        }
+        Ok(panel)
+    }
+
+    /// The length of the longest k-mers of the junctions accepted.
+    pub fn longest(&self) -> Option<usize> {
+        self.groups.last().map(|group| group.kmer_size)
+    }
+
+    /// Adds a junction, noting which of them is credited with the hits
+    /// on each of its k-mers, unless it is rejected.
+    pub fn add(&mut self, name: &str, seq: &str) {
+        let PanelOptions {
+            alphabet, strand, ..
+        } = self.options;
+
+        let junction = || Junction {
+            name: name.to_string(),
+            seq: seq.to_string(),
+        };
+
+        let kmer_size = match self.options.kmer_size {
+            Some(kmer_size) => kmer_size,
+            None if (1..=K::MAX_KMER_SIZE).contains(&seq.len()) => seq.len(),
+            None => {
+                self.rejected.push((junction(), Rejection::Size(seq.len())));
+                return;
+            }
+        };
+
+        let comps = match self.options.ambiguous {
+            Ambiguous::Reject if seq.len() != kmer_size => {
+                Err(Rejection::Length {
+                    len: seq.len(),
+                    kmer_size,
+                })
+            }
+            Ambiguous::Reject => alphabet
+                .compress::<K>(seq, kmer_size)
+                .map(|comp| vec![comp])
+                .ok_or(Rejection::Ambiguous(kmer_size)),
+            Ambiguous::Expand => {
+                alphabet.expand(seq, kmer_size, self.options.max_expansions)
+            }
+        };
+
+        let comps = match comps {
+            Ok(comps) => comps,
+            Err(e) => {
+                self.rejected.push((junction(), e));
+                return;
+            }
+        };
+
+        // Every k-mer of an expanded junction maps back to its name
+        let index = self.names.len();
+        self.names.push(name.to_string());
+        self.seqs.push(seq.to_string());
+
+        let group = match self
+            .groups
+            .binary_search_by_key(&kmer_size, |group| group.kmer_size)
+        {
+            Ok(group) => group,
+            Err(group) => {
+                self.groups.insert(
+                    group,
+                    Group {
+                        kmer_size,
+                        credits: HashMap::new(),
+                        junctions: vec![],
+                    },
+                );
+                group
+            }
+        };
+        let Group {
+            credits: group_credits,
+            junctions,
+            ..
+        } = &mut self.groups[group];
+
+        let mut duplicated = false;
+        for comp in &comps {
+            // Look names up by the key the search will report,
+            // noting when that key is the junction's reverse
+            // complement so the strands can be reported as given.
+            let key = strand.junction_key(*comp, kmer_size);
+            let credits = group_credits.entry(key).or_insert_with(|| {
+                junctions.push(*comp);
+                vec![]
+            });
+
+            if credits.iter().any(|credit| credit.exact) {
+                duplicated |=
+                    credits.iter().any(|credit| credit.junction != index);
+            } else {
+                credits.push(Credit {
+                    junction: index,
+                    flipped: key != *comp,
+                    exact: true,
+                });
+            }
+        }
+
+        if duplicated {
+            self.duplicated.push(junction());
+        }
+
+        // Credit the junction with hits on any k-mer that is close
+        // enough and not already one of its own.
+        let max_mismatches = self.options.max_mismatches as usize;
+        for comp in &comps {
+            for neighbor in neighbors(*comp, kmer_size, max_mismatches) {
+                let key = strand.junction_key(neighbor, kmer_size);
+                let credits = group_credits.entry(key).or_insert_with(|| {
+                    junctions.push(neighbor);
+                    vec![]
+                });
+
+                if credits.iter().all(|credit| credit.junction != index) {
+                    credits.push(Credit {
+                        junction: index,
+                        flipped: key != neighbor,
+                        exact: false,
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl Panel<WideCompressedSeq> {
+    /// The same panel with its k-mers held in `CompressedSeq`s, which
+    /// must be long enough for the longest of them.
+    pub fn narrow(self) -> Panel<CompressedSeq> {
+        assert!(self
+            .longest()
+            .is_none_or(|k| k <= CompressedSeq::MAX_KMER_SIZE));
+
+        // A short k-mer takes up only the low bits of a wide one
+        let groups = self
+            .groups
+            .into_iter()
+            .map(|group| Group {
+                kmer_size: group.kmer_size,
+                credits: group
+                    .credits
+                    .into_iter()
+                    .map(|(key, credits)| (key as CompressedSeq, credits))
+                    .collect(),
+                junctions: group
+                    .junctions
+                    .into_iter()
+                    .map(|comp| comp as CompressedSeq)
+                    .collect(),
+            })
+            .collect();
+
+        Panel {
+            options: self.options,
+            names: self.names,
+            seqs: self.seqs,
+            groups,
+            rejected: self.rejected,
+            duplicated: self.duplicated,
+        }
+    }
+}
+
+/// Opens a FASTA or FASTQ file, which may be compressed.
+pub(crate) fn get_reader(filename: &str) -> Result<kseq::Paths<'_>> {
+    parse_path(filename).map_err(|source| Error::Io {
+        path: filename.to_string(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compress::{compress_seq, Alphabet},
+        error::Rejection,
+        panel::{Ambiguous, Panel, PanelOptions},
+        search::Strand,
+    };
+
+    fn options(kmer_size: Option<usize>) -> PanelOptions {
+        PanelOptions {
+            alphabet: Alphabet::default(),
+            kmer_size,
+            strand: Strand::Forward,
+            ambiguous: Ambiguous::Reject,
+            max_expansions: 256,
+            max_mismatches: 0,
+        }
+    }
+
+    #[test]
+    fn panel_add() {
+        let mut panel: Panel<u64> = Panel::new(options(Some(4)));
+        panel.add("one", "ACGT");
+        panel.add("short", "ACG");
+        panel.add("again", "ACGT");
+        panel.add("two", "TTTT");
+        panel.add("ambiguous", "ACNT");
+
+        assert_eq!(panel.names, ["one", "again", "two"]);
+        assert_eq!(panel.seqs, ["ACGT", "ACGT", "TTTT"]);
+        assert_eq!(panel.groups.len(), 1);
+        assert_eq!(panel.groups[0].junctions.len(), 2);
+        assert_eq!(panel.rejected.len(), 2);
+        assert_eq!(panel.rejected[0].0.name, "short");
+        assert_eq!(
+            panel.rejected[0].1,
+            Rejection::Length {
+                len: 3,
+                kmer_size: 4
+            }
+        );
+        assert_eq!(panel.rejected[1].1, Rejection::Ambiguous(4));
+        assert_eq!(panel.duplicated.len(), 1);
+        assert_eq!(panel.duplicated[0].name, "again");
+
+        // The first junction keeps the hits on a shared k-mer
+        let key = compress_seq("ACGT", 4).unwrap();
+        let credits = &panel.groups[0].credits[&key];
+        assert_eq!(credits.len(), 1);
+        assert_eq!(credits[0].junction, 0);
+    }
+
+    #[test]
+    fn panel_expand() {
+        let mut panel: Panel<u64> = Panel::new(PanelOptions {
+            ambiguous: Ambiguous::Expand,
+            max_expansions: 4,
+            ..options(Some(3))
+        });
+        panel.add("two", "ACN");
+        panel.add("many", "NNN");
+
+        assert_eq!(panel.names, ["two"]);
+        assert_eq!(panel.groups[0].junctions.len(), 4);
+        assert_eq!(panel.rejected[0].1, Rejection::TooManyExpansions(4));
+    }
+
+    #[test]
+    fn panel_lengths() {
+        // Without a k-mer size each junction is matched at its own
+        // length, in a group for that length
+        let mut panel: Panel<u128> = Panel::new(options(None));
+        panel.add("long", &"ACGT".repeat(12));
+        panel.add("one", "ACGT");
+        panel.add("again", "ACGT");
+        panel.add("two", "TTTT");
+        panel.add("short", "AC");
+        panel.add("empty", "");
+        panel.add("too_long", &"A".repeat(65));
+
+        assert_eq!(panel.names, ["long", "one", "again", "two", "short"]);
+        let sizes: Vec<_> =
+            panel.groups.iter().map(|group| group.kmer_size).collect();
+        assert_eq!(sizes, [2, 4, 48]);
+        assert_eq!(panel.groups[1].junctions.len(), 2);
+        assert_eq!(panel.duplicated[0].name, "again");
+
+        // A k-mer of one length is not taken for one of another
+        let key = compress_seq::<u128>("ACGT", 4).unwrap();
+        assert_eq!(panel.groups[1].credits[&key][0].junction, 1);
+        assert!(!panel.groups[0].credits.contains_key(&key));
+
+        let rejected: Vec<_> =
+            panel.rejected.iter().map(|(_, e)| e.clone()).collect();
+        assert_eq!(rejected, [Rejection::Size(0), Rejection::Size(65)]);
+    }
+
+    #[test]
+    fn panel_narrow() {
+        // A junction too long for any k-mer does not keep the rest from
+        // fitting in narrower ones
+        let mut panel: Panel<u128> = Panel::new(options(None));
+        panel.add("one", "ACGT");
+        panel.add("too_long", &"A".repeat(65));
+        assert_eq!(panel.longest(), Some(4));
+
+        let panel = panel.narrow();
+        let key = compress_seq::<u64>("ACGT", 4).unwrap();
+        assert_eq!(panel.groups[0].junctions, [key]);
+        assert_eq!(panel.groups[0].credits[&key][0].junction, 0);
+        assert_eq!(panel.rejected[0].1, Rejection::Size(65));
+    }
+}
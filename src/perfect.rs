@@ -0,0 +1,268 @@
+use crate::{
+    compress::{CompressedSeq, Kmer},
+    error::{Error, IndexError, Result},
+    hash::Table,
+    index::{read_bytes, read_kmers, read_u64, write_kmers, write_u64},
+};
+use std::io::{self, Read, Write};
+
+/// Average number of keys sharing a pilot. Larger buckets take less
+/// memory for pilots but longer to build.
+const BUCKET_SIZE: usize = 4;
+
+/// Pilots tried for a bucket for each key, before giving up on finding
+/// one. Even the last key, left with a single free slot, is all but
+/// sure to be placed well before then.
+const PILOTS_PER_KEY: u64 = 64;
+
+/// A minimal perfect hash of a fixed set of keys, built in the manner
+/// of PTHash: keys are split into buckets, and each bucket is given a
+/// pilot that sends all of its keys to free slots. There are exactly as
+/// many slots as keys, so no memory is spent on empty slots, and a
+/// lookup reads one pilot and then goes straight to its only candidate
+/// slot. The key is stored in the slot to turn away k-mers that are
+/// not in the set.
+#[derive(Debug, Clone)]
+pub struct PerfectHash<K: Kmer = CompressedSeq> {
+    pilots: Vec<u32>,
+    pub(crate) key: Vec<K>,
+}
+
+/// Maps `x` onto `0..n` using its high bits, which is quicker than `%`.
+#[inline]
+fn reduce(x: u64, n: usize) -> usize {
+    ((x as u128 * n as u128) >> 64) as usize
+}
+
+impl<K: Kmer> PerfectHash<K> {
+    /// Builds a perfect hash of `keys`. Duplicated keys are only stored
+    /// once.
+    pub fn new(keys: &[K]) -> Result<PerfectHash<K>> {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+
+        let num_buckets = keys.len().div_ceil(BUCKET_SIZE).max(1);
+        let mut buckets = vec![vec![]; num_buckets];
+        for key in &keys {
+            let mixed = key.mix();
+            buckets[reduce(mixed, num_buckets)].push(mixed);
+        }
+
+        // Keys that mix to the same value are sent to the same slot by
+        // every pilot, so there is no placing them
+        for bucket in &mut buckets {
+            bucket.sort_unstable();
+            if bucket.windows(2).any(|pair| pair[0] == pair[1]) {
+                return Err(Error::NoPerfectHash(keys.len()));
+            }
+        }
+
+        // The biggest buckets are the hardest to place, so they go
+        // while the table is still empty.
+        let mut order: Vec<usize> = (0..num_buckets).collect();
+        order.sort_by_key(|bucket| std::cmp::Reverse(buckets[*bucket].len()));
+
+        let max_pilot = (keys.len() as u64 * PILOTS_PER_KEY)
+            .clamp(1 << 16, u32::MAX as u64) as u32;
+        let mut pilots = vec![0; num_buckets];
+        let mut taken = vec![false; keys.len()];
+        let mut slots = vec![];
+        for bucket in order {
+            if buckets[bucket].is_empty() {
+                break;
+            }
+
+            let found = (0..max_pilot).find(|pilot| {
+                slots.clear();
+                for mixed in &buckets[bucket] {
+                    let slot = Self::slot(*mixed, *pilot, keys.len());
+                    if taken[slot] || slots.contains(&slot) {
+                        return false;
+                    }
+                    slots.push(slot);
+                }
+                true
+            });
+
+            match found {
+                Some(pilot) => pilots[bucket] = pilot,
+                _ => return Err(Error::NoPerfectHash(keys.len())),
+            }
+
+            for slot in &slots {
+                taken[*slot] = true;
+            }
+        }
+
+        let mut perfect = PerfectHash {
+            pilots,
+            key: vec![K::default(); keys.len()],
+        };
+
+        for key in keys {
+            let slot = perfect.position(key);
+            perfect.key[slot] = key;
+        }
+
+        Ok(perfect)
+    }
+
+    /// The slot a key with the mixed value `mixed` is sent to by `pilot`.
+    #[inline]
+    fn slot(mixed: u64, pilot: u32, num_slots: usize) -> usize {
+        reduce((mixed ^ (pilot as u64).mix()).mix(), num_slots)
+    }
+
+    /// The only slot `value` can be in.
+    #[inline]
+    fn position(&self, value: K) -> usize {
+        let mixed = value.mix();
+        let pilot = self.pilots[reduce(mixed, self.pilots.len())];
+        Self::slot(mixed, pilot, self.key.len())
+    }
+}
+
+impl<K: Kmer> Table<K> for PerfectHash<K> {
+    fn build(keys: &[K]) -> Result<Self> {
+        PerfectHash::new(keys)
+    }
+
+    #[inline]
+    fn find(&self, value: K) -> Option<usize> {
+        if self.key.is_empty() {
+            return None;
+        }
+
+        let slot = self.position(value);
+        (self.key[slot] == value).then_some(slot)
+    }
+
+    fn capacity(&self) -> usize {
+        self.key.len()
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (usize, K)> + '_ {
+        self.key.iter().copied().enumerate()
+    }
+
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        write_u64(out, self.pilots.len() as u64)?;
+        for pilot in &self.pilots {
+            out.write_all(&pilot.to_le_bytes())?;
+        }
+        write_kmers(out, &self.key)
+    }
+
+    fn read(input: &mut impl Read) -> Result<Self, IndexError> {
+        let len = read_u64(input)?;
+        let pilots: Vec<u32> = read_bytes(input, len.saturating_mul(4))?
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        if pilots.is_empty() {
+            return Err(IndexError::Malformed(
+                "perfect hash has no pilots".to_string(),
+            ));
+        }
+
+        Ok(PerfectHash {
+            pilots,
+            key: read_kmers(input)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compress::{Kmer, WideCompressedSeq};
+    use crate::hash::Table;
+    use crate::perfect::PerfectHash;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn perfect_create() {
+        let keys: Vec<u64> = (0..1000).map(|key| key * 7).collect();
+        let hash: PerfectHash = PerfectHash::new(&keys).unwrap();
+
+        // One slot for every key
+        assert_eq!(hash.capacity(), 1000);
+        assert_eq!(hash.pilots.len(), 250);
+
+        let mut slots: Vec<_> =
+            keys.iter().map(|key| hash.find(*key).unwrap()).collect();
+        slots.sort_unstable();
+        assert_eq!(slots, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn perfect_misses() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let keys: Vec<u64> = (0..500).map(|_| rng.gen()).collect();
+        let hash: PerfectHash = PerfectHash::new(&keys).unwrap();
+
+        for key in &keys {
+            assert!(hash.find(*key).is_some());
+            assert!(hash.find(key ^ 1).is_none());
+        }
+    }
+
+    #[test]
+    fn perfect_poly_a() {
+        // Poly-A is key 0, which every slot starts out holding
+        let hash: PerfectHash = PerfectHash::new(&[5, 6, 7]).unwrap();
+        assert!(hash.find(0).is_none());
+
+        let hash: PerfectHash = PerfectHash::new(&[0, 5, 6, 7]).unwrap();
+        assert!(hash.find(0).is_some());
+    }
+
+    #[test]
+    fn perfect_small() {
+        let hash: PerfectHash = PerfectHash::new(&[]).unwrap();
+        assert!(hash.find(0).is_none());
+        assert_eq!(hash.entries().count(), 0);
+
+        // Duplicates are only stored once
+        let hash: PerfectHash = PerfectHash::new(&[3, 3, 3]).unwrap();
+        assert_eq!(hash.key, [3]);
+    }
+
+    #[test]
+    fn perfect_entries() {
+        let hash: PerfectHash = Table::build(&[10, 11, 0]).unwrap();
+
+        let mut entries: Vec<_> = hash.entries().collect();
+        for (index, key) in &entries {
+            assert_eq!(hash.find(*key), Some(*index));
+        }
+
+        entries.sort_unstable_by_key(|(_, key)| *key);
+        let keys: Vec<_> = entries.iter().map(|(_, key)| *key).collect();
+        assert_eq!(keys, [0, 10, 11]);
+        assert!(hash.find(1).is_none());
+    }
+
+    #[test]
+    fn perfect_wide_keys() {
+        let wide = u128::MAX - 5;
+        let keys = [wide, 11, wide - 10, wide ^ (1 << 64)];
+        let hash: PerfectHash<WideCompressedSeq> =
+            PerfectHash::new(&keys).unwrap();
+
+        for val in &keys {
+            assert_eq!(hash.key[hash.find(*val).unwrap()], *val);
+        }
+        assert!(hash.find(1).is_none());
+    }
+
+    #[test]
+    fn perfect_same_mix() {
+        // Two wide keys that mix to the same value can't both be placed,
+        // which is found out before trying any pilots
+        let keys = [0, 1 << 64 | 1u64.mix() as WideCompressedSeq];
+        assert_eq!(keys[0].mix(), keys[1].mix());
+        let err = PerfectHash::new(&keys).unwrap_err();
+        assert_eq!(err.to_string(), "no perfect hash for 2 keys");
+    }
+}
@@ -1,113 +1,809 @@
 use crate::{
-    compress::encode_char, constants::HASH_CAPACITY_MULTIPLE, hash::Hash,
+    bloom::BloomFilter,
+    compress::{
+        canonical, kmer_mask, Alphabet, CompressedSeq, Kmer, INVALID_BASE,
+    },
+    counts::{Count, Counts, Hits},
+    error::Result,
+    hash::{Hash, Table},
 };
-use anyhow::Result;
+use clap::ValueEnum;
 
-pub struct Search {
+/// Which orientation(s) of each read are searched for junctions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Strand {
+    /// Only the read as given
+    #[default]
+    Forward,
+    /// Only the reverse complement of the read
+    Reverse,
+    /// The read and its reverse complement
+    Both,
+    /// The lesser of each k-mer and its reverse complement
+    Canonical,
+}
+
+impl Strand {
+    /// Returns the key under which a junction is stored for this mode.
+    /// Canonical searches store each junction as its canonical k-mer,
+    /// every other mode stores the junction as given.
+    pub fn junction_key<K: Kmer>(self, junction: K, kmer_size: usize) -> K {
+        match self {
+            Strand::Canonical => canonical(junction, kmer_size),
+            _ => junction,
+        }
+    }
+}
+
+/// The junction k-mers to search for. The index never changes once
+/// built, so one can be shared by every search, with each keeping its
+/// own counts.
+pub struct JunctionIndex<K: Kmer = CompressedSeq, T: Table<K> = Hash<K>> {
+    pub table: T,
+    prefilter: Option<BloomFilter<K>>,
+    kmer_size: usize,
+    strand: Strand,
+}
+
+impl<K: Kmer, T: Table<K>> JunctionIndex<K, T> {
+    pub fn new(
+        junctions: &[K],
+        kmer_size: usize,
+        strand: Strand,
+    ) -> Result<JunctionIndex<K, T>> {
+        let keys: Vec<K> = junctions
+            .iter()
+            .map(|seq| strand.junction_key(*seq, kmer_size))
+            .collect();
+
+        Ok(JunctionIndex::from_table(
+            T::build(&keys)?,
+            kmer_size,
+            strand,
+        ))
+    }
+
+    /// An index of a table that was already built, keyed for `strand`.
+    pub fn from_table(
+        table: T,
+        kmer_size: usize,
+        strand: Strand,
+    ) -> JunctionIndex<K, T> {
+        JunctionIndex {
+            table,
+            prefilter: None,
+            kmer_size,
+            strand,
+        }
+    }
+
+    /// The length of the junction k-mers.
+    pub fn kmer_size(&self) -> usize {
+        self.kmer_size
+    }
+
+    /// Check every window against a Bloom filter of the junctions,
+    /// with a false positive rate of about `fp_prob`, before looking it
+    /// up. Most windows are not junctions, and the filter can turn
+    /// them away without leaving the cache.
+    pub fn with_prefilter(mut self, fp_prob: f64) -> JunctionIndex<K, T> {
+        let keys: Vec<K> = self.table.entries().map(|(_, key)| key).collect();
+        let mut filter = BloomFilter::new(keys.len(), fp_prob);
+        for key in keys {
+            filter.add(key);
+        }
+        self.prefilter = Some(filter);
+        self
+    }
+
+    /// Whether `kmer` could be a junction and is worth looking up.
+    #[inline]
+    fn admit(&self, kmer: K) -> bool {
+        self.prefilter
+            .as_ref()
+            .is_none_or(|filter| filter.check(kmer))
+    }
+}
+
+/// Searches reads for the junctions of a shared index, counting hits
+/// in `counts`, which are its own unless they are atomic and shared.
+pub struct Search<
+    'a,
+    K: Kmer = CompressedSeq,
+    T: Table<K> = Hash<K>,
+    H: Hits = Counts,
+> {
+    alphabet: Alphabet,
+    pub counts: H,
     haystack_index: usize,
     haystack_size: usize,
-    haystack_window: u64,
-    pub junctions: Hash,
+    haystack_window: K,
+    index: &'a JunctionIndex<K, T>,
+    kmer_size: usize,
+    kmer_mask: K,
+    rc_shift: usize,
+    rc_window: K,
     start_index: usize,
+    strand: Strand,
+    /// Windows looked for in the junctions
+    pub lookups: u64,
+    /// Lookups the prefilter turned away
+    pub rejected: u64,
 }
 
-impl Search {
-    pub fn new(junctions: &Vec<u64>) -> Result<Search> {
-        let mut junction_hash =
-            Hash::new(junctions.len() * HASH_CAPACITY_MULTIPLE);
+impl<'a, K: Kmer, T: Table<K>, C: Count> Search<'a, K, T, Counts<C>> {
+    pub fn new(
+        index: &'a JunctionIndex<K, T>,
+        alphabet: Alphabet,
+    ) -> Search<'a, K, T, Counts<C>> {
+        Search::with_counts(
+            index,
+            alphabet,
+            Counts::new(index.table.capacity()),
+        )
+    }
 
-        for seq in junctions {
-            junction_hash.add(*seq)?;
-        }
+    /// Adds the hits and lookups of another search of the same index,
+    /// such as one that went through a different share of the reads.
+    pub fn merge(&mut self, other: &Search<'a, K, T, Counts<C>>) {
+        self.counts.merge(&other.counts);
+        self.lookups += other.lookups;
+        self.rejected += other.rejected;
+    }
+}
 
-        Ok(Search {
+impl<'a, K: Kmer, T: Table<K>, H: Hits> Search<'a, K, T, H> {
+    /// A search counting into `counts`, which must have a slot for
+    /// every slot of the index.
+    pub fn with_counts(
+        index: &'a JunctionIndex<K, T>,
+        alphabet: Alphabet,
+        counts: H,
+    ) -> Search<'a, K, T, H> {
+        Search {
+            alphabet,
+            counts,
             haystack_index: 0,
             haystack_size: 0,
-            haystack_window: 0,
-            junctions: junction_hash,
+            haystack_window: K::default(),
+            index,
+            kmer_size: index.kmer_size,
+            kmer_mask: kmer_mask(index.kmer_size),
+            rc_shift: 2 * (index.kmer_size - 1),
+            rc_window: K::default(),
             start_index: 0,
+            strand: index.strand,
+            lookups: 0,
+            rejected: 0,
+        }
+    }
+
+    /// The length of the junction k-mers searched for.
+    pub fn kmer_size(&self) -> usize {
+        self.kmer_size
+    }
+
+    /// The slot of `kmer` in the index, unless it is not a junction.
+    #[inline]
+    fn find(&mut self, kmer: K) -> Option<usize> {
+        self.lookups += 1;
+        if !self.index.admit(kmer) {
+            self.rejected += 1;
+            return None;
+        }
+        self.index.table.find(kmer)
+    }
+
+    #[inline]
+    fn inc_hits(&mut self, kmer: K) {
+        if let Some(index) = self.find(kmer) {
+            self.counts.inc_hits(index);
+        }
+    }
+
+    #[inline]
+    fn inc_rc_hits(&mut self, kmer: K) {
+        if let Some(index) = self.find(kmer) {
+            self.counts.inc_rc_hits(index);
+        }
+    }
+
+    /// Forward strand hits on the junction stored as `key`.
+    pub fn get_hits(&self, key: K) -> Option<u64> {
+        let index = self.index.table.find(key)?;
+        Some(self.counts.get_hits(index))
+    }
+
+    /// Reverse complement hits on the junction stored as `key`.
+    pub fn get_rc_hits(&self, key: K) -> Option<u64> {
+        let index = self.index.table.find(key)?;
+        Some(self.counts.get_rc_hits(index))
+    }
+
+    /// Every junction key along with its forward and reverse complement
+    /// hits, in slot order.
+    pub fn entries(&self) -> impl Iterator<Item = (K, u64, u64)> + '_ {
+        self.index.table.entries().map(|(index, key)| {
+            (
+                key,
+                self.counts.get_hits(index),
+                self.counts.get_rc_hits(index),
+            )
         })
     }
 
-    pub fn search(&mut self, sequence: &str) {
-        let sequence: Vec<char> = sequence.chars().collect();
+    /// Count the junctions found in `sequence`. The sequence is read a
+    /// byte at a time, so anything outside the alphabet, including the
+    /// bytes of a multibyte character, just breaks up the windows.
+    pub fn search(&mut self, sequence: &[u8]) {
+        // A read shorter than k can't hold a junction.
+        if sequence.len() < self.kmer_size {
+            return;
+        }
 
         // Reset in preparation for the search.
         self.haystack_index = 0;
         self.haystack_size = sequence.len();
-        self.haystack_window = 0;
+        self.haystack_window = K::default();
+        self.rc_window = K::default();
         self.start_index = 0;
 
-        // If we don't have at least 32 nucleotides remaining, we
+        // If we don't have at least k nucleotides remaining, we
         // know we are finished.
-        'search: while self.start_index <= self.haystack_size - 32 {
-            // Bootstrap by encoding the next 31 nucleotides if we
+        'search: while self.start_index + self.kmer_size <= self.haystack_size
+        {
+            // Bootstrap by encoding the next k - 1 nucleotides if we
             // haven't done it yet. This happens at the beginning of
             // a search and immediately after a bad character has
             // been encountered. We can ignore the possibility of a
             // missing alphabet character since we've already dealt
             // with the other (valid) possibility above.
-            while self.haystack_index < self.start_index + 32 {
-                let mask = encode_char(sequence[self.haystack_index]);
+            while self.haystack_index < self.start_index + self.kmer_size {
+                let mask =
+                    self.alphabet.encode(sequence[self.haystack_index]);
 
                 // If we find a bad character, we basically just restart
                 // the search from the next character.
-                if mask == 255 {
+                if mask == INVALID_BASE {
                     self.start_index = self.haystack_index + 1;
                     self.haystack_index = self.start_index;
                     continue 'search;
                 }
 
-                self.haystack_window = (self.haystack_window << 2) | mask;
+                self.haystack_window = ((self.haystack_window << 2)
+                    | K::from(mask))
+                    & self.kmer_mask;
+
+                // The reverse complement window grows from the other
+                // end, with each new base complemented.
+                self.rc_window = (self.rc_window >> 2)
+                    | (K::from(mask ^ 3) << self.rc_shift);
                 self.haystack_index += 1;
             }
 
             // Bump the start index in order to slide the window one
             // nucleotide to the right.
             self.start_index += 1;
-            let _ = self.junctions.inc_hits(self.haystack_window);
+
+            match self.strand {
+                Strand::Forward => self.inc_hits(self.haystack_window),
+                Strand::Reverse => self.inc_rc_hits(self.rc_window),
+                Strand::Both => {
+                    self.inc_hits(self.haystack_window);
+
+                    // A palindrome is the same k-mer on both strands and
+                    // should only be counted once.
+                    if self.rc_window != self.haystack_window {
+                        self.inc_rc_hits(self.rc_window);
+                    }
+                }
+                Strand::Canonical => {
+                    if self.haystack_window <= self.rc_window {
+                        self.inc_hits(self.haystack_window);
+                    } else {
+                        self.inc_rc_hits(self.rc_window);
+                    }
+                }
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{compress::compress_seq, search::Search};
+    use crate::{
+        compress::{
+            compress_seq, Alphabet, CompressedSeq, WideCompressedSeq,
+        },
+        counts::AtomicCounts,
+        hash::Table,
+        perfect::PerfectHash,
+        search::{JunctionIndex, Search, Strand},
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::thread;
 
     #[test]
     fn test_search() {
-        let junctions = vec![
-            compress_seq(&"T".repeat(32)).unwrap(),
-            compress_seq(&"G".repeat(32)).unwrap(),
+        let junctions: Vec<CompressedSeq> = vec![
+            compress_seq(&"T".repeat(32), 32).unwrap(),
+            compress_seq(&"G".repeat(32), 32).unwrap(),
         ];
-        let mut search = Search::new(&junctions).unwrap();
-        search.search("AAGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGAA");
+        let index =
+            JunctionIndex::new(&junctions, 32, Strand::Forward).unwrap();
+        let mut search: Search<_> = Search::new(&index, Alphabet::default());
+        search.search(b"AAGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGGAA");
 
-        let res = search.junctions.get_hits(junctions[0]);
+        let res = search.get_hits(junctions[0]);
         assert_eq!(res, Some(0));
 
-        let res = search.junctions.get_hits(junctions[1]);
+        let res = search.get_hits(junctions[1]);
         assert_eq!(res, Some(1));
 
-        let missing = compress_seq(&"C".repeat(32)).unwrap();
-        let res = search.junctions.get_hits(missing);
+        let missing =
+            compress_seq::<CompressedSeq>(&"C".repeat(32), 32).unwrap();
+        let res = search.get_hits(missing);
         assert!(res.is_none());
     }
 
     #[test]
     fn test_search_with_n() {
-        let junctions = vec![
-            compress_seq(&"ACGT".repeat(8)).unwrap(),
-            compress_seq(&"G".repeat(32)).unwrap(),
+        let junctions: Vec<CompressedSeq> = vec![
+            compress_seq(&"ACGT".repeat(8), 32).unwrap(),
+            compress_seq(&"G".repeat(32), 32).unwrap(),
         ];
-        let mut search = Search::new(&junctions).unwrap();
+        let index =
+            JunctionIndex::new(&junctions, 32, Strand::Forward).unwrap();
+        let mut search: Search<_> = Search::new(&index, Alphabet::default());
 
-        search.search("AANACGTACGTACGTACGTACGTACGTACGTACGTNNAA");
+        search.search(b"AANACGTACGTACGTACGTACGTACGTACGTACGTNNAA");
 
-        let res = search.junctions.get_hits(junctions[0]);
+        let res = search.get_hits(junctions[0]);
         assert_eq!(res, Some(1));
 
-        let res = search.junctions.get_hits(junctions[1]);
+        let res = search.get_hits(junctions[1]);
         assert_eq!(res, Some(0));
     }
+
+    #[test]
+    fn test_search_kmer_sizes() {
+        let haystack = "NNACGTTGCAAGGCTTAACCGGTATCGATCGGATCCAGTN";
+
+        for k in [1, 21, 25, 27, 32] {
+            let needle = &haystack[5..5 + k];
+            let junctions: Vec<CompressedSeq> =
+                vec![compress_seq(needle, k).unwrap()];
+            let index =
+                JunctionIndex::new(&junctions, k, Strand::Forward).unwrap();
+            let mut search: Search<_> =
+                Search::new(&index, Alphabet::default());
+            search.search(haystack.as_bytes());
+
+            let expected = haystack
+                .as_bytes()
+                .windows(k)
+                .filter(|window| *window == needle.as_bytes())
+                .count() as u64;
+            let res = search.get_hits(junctions[0]);
+            assert_eq!(res, Some(expected), "k = {k}");
+        }
+    }
+
+    #[test]
+    fn test_search_kmer_size_masks_window() {
+        // Bases that slide out of a 21-mer window must not linger in the
+        // high bits, or none of these windows would match.
+        let junctions: Vec<CompressedSeq> =
+            vec![compress_seq(&"C".repeat(21), 21).unwrap()];
+        let index =
+            JunctionIndex::new(&junctions, 21, Strand::Forward).unwrap();
+        let mut search: Search<_> = Search::new(&index, Alphabet::default());
+        search.search(
+            format!("{}{}", "G".repeat(10), "C".repeat(23)).as_bytes(),
+        );
+
+        let res = search.get_hits(junctions[0]);
+        assert_eq!(res, Some(3));
+    }
+
+    #[test]
+    fn test_search_wide_kmers() {
+        let haystack = format!(
+            "{}N{}{}",
+            "ACGT".repeat(12),
+            "GATTACA".repeat(12),
+            "C".repeat(70),
+        );
+        let count = |needle: &str| {
+            haystack
+                .as_bytes()
+                .windows(needle.len())
+                .filter(|window| *window == needle.as_bytes())
+                .count() as u64
+        };
+
+        for k in [33, 40, 63, 64] {
+            let needles = [
+                "GATTACA".repeat(10)[..k].to_string(),
+                "ACGT".repeat(16)[..k].to_string(),
+                "C".repeat(k),
+            ];
+            let junctions: Vec<WideCompressedSeq> = needles
+                .iter()
+                .map(|needle| compress_seq(needle, k).unwrap())
+                .collect();
+            let index =
+                JunctionIndex::new(&junctions, k, Strand::Forward).unwrap();
+            let mut search: Search<_> =
+                Search::new(&index, Alphabet::default());
+            search.search(haystack.as_bytes());
+
+            for (needle, junction) in needles.iter().zip(&junctions) {
+                let res = search.get_hits(*junction);
+                assert_eq!(res, Some(count(needle)), "{needle}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_search_strands() {
+        let fwd = "GATTACAGATTACAGATTACAG";
+        let rev = "CTGTAATCTGTAATCTGTAATC";
+        let palindrome = "AACGTACGTACGTACGTACGTT";
+        let haystack = format!("{fwd}N{rev}N{rev}N{palindrome}");
+        let k = fwd.len();
+
+        // Forward and reverse complement hits for each junction. The
+        // canonical key for `fwd` and `rev` is `rev`, so hits on either
+        // are counted on the strand that matches `rev`.
+        for (strand, expected) in [
+            (Strand::Forward, [(1, 0), (2, 0), (1, 0)]),
+            (Strand::Reverse, [(0, 2), (0, 1), (0, 1)]),
+            (Strand::Both, [(1, 2), (2, 1), (1, 0)]),
+            (Strand::Canonical, [(2, 1), (2, 1), (1, 0)]),
+        ] {
+            let junctions: Vec<CompressedSeq> = [fwd, rev, palindrome]
+                .iter()
+                .map(|seq| compress_seq(seq, k).unwrap())
+                .collect();
+
+            // Canonical mode can only hold one of a reverse complement
+            // pair, so look the reverse up by its forward junction.
+            let junctions = match strand {
+                Strand::Canonical => vec![junctions[0], junctions[2]],
+                _ => junctions,
+            };
+            let index = JunctionIndex::new(&junctions, k, strand).unwrap();
+            let mut search: Search<_> =
+                Search::new(&index, Alphabet::default());
+            search.search(haystack.as_bytes());
+
+            let hits: Vec<_> = [fwd, rev, palindrome]
+                .iter()
+                .map(|seq| {
+                    let junction = compress_seq(seq, k).unwrap();
+                    let key = strand.junction_key(junction, k);
+                    (
+                        search.get_hits(key).unwrap(),
+                        search.get_rc_hits(key).unwrap(),
+                    )
+                })
+                .collect();
+            assert_eq!(hits, expected, "{strand:?}");
+        }
+    }
+
+    #[test]
+    fn test_search_strands_wide() {
+        let fwd = "GATTACA".repeat(7);
+        let rev = "TGTAATC".repeat(7);
+        let k = fwd.len();
+        let junctions: Vec<WideCompressedSeq> =
+            vec![compress_seq(&fwd, k).unwrap()];
+
+        for strand in [Strand::Reverse, Strand::Both, Strand::Canonical] {
+            let index = JunctionIndex::new(&junctions, k, strand).unwrap();
+            let mut search: Search<_> =
+                Search::new(&index, Alphabet::default());
+            search.search(format!("AA{rev}TT").as_bytes());
+
+            let key = strand.junction_key(junctions[0], k);
+            let hits = search.get_hits(key).unwrap()
+                + search.get_rc_hits(key).unwrap();
+            assert_eq!(hits, 1);
+        }
+    }
+
+    #[test]
+    fn test_search_uracil() {
+        let junctions: Vec<CompressedSeq> =
+            vec![compress_seq(&"GATTACA".repeat(3), 21).unwrap()];
+        let haystack =
+            format!("CC{}CCgauuacaGAUUACAgauuaca", "GAUUACA".repeat(3));
+
+        let index =
+            JunctionIndex::new(&junctions, 21, Strand::Forward).unwrap();
+
+        let mut search: Search<_> = Search::new(&index, Alphabet::new(true));
+        search.search(haystack.as_bytes());
+        assert_eq!(search.get_hits(junctions[0]), Some(2));
+
+        // Without uracil, every U breaks up the windows
+        let index =
+            JunctionIndex::new(&junctions, 21, Strand::Forward).unwrap();
+        let mut search: Search<_> = Search::new(&index, Alphabet::new(false));
+        search.search(haystack.as_bytes());
+        assert_eq!(search.get_hits(junctions[0]), Some(0));
+    }
+
+    #[test]
+    fn test_search_non_ascii() {
+        let junctions: Vec<CompressedSeq> =
+            vec![compress_seq(&"ACGT".repeat(3), 12).unwrap()];
+        let index =
+            JunctionIndex::new(&junctions, 12, Strand::Forward).unwrap();
+        let mut search: Search<_> = Search::new(&index, Alphabet::default());
+
+        // Multibyte characters restart the window like any other bad base
+        let haystack = format!(
+            "ACGTAC€GTACGTé{}\u{ff}{}ñ",
+            "ACGT".repeat(3),
+            "ACGT".repeat(3)
+        );
+        search.search(haystack.as_bytes());
+        assert_eq!(search.get_hits(junctions[0]), Some(2));
+
+        // Invalid UTF-8 is just more bad bases
+        search.search(b"\xff\xfeACGTACGTACGT\x80");
+        assert_eq!(search.get_hits(junctions[0]), Some(3));
+    }
+
+    #[test]
+    fn test_search_short_reads() {
+        let junctions: Vec<CompressedSeq> =
+            vec![compress_seq(&"C".repeat(32), 32).unwrap()];
+        let index = JunctionIndex::new(&junctions, 32, Strand::Both).unwrap();
+        let mut search: Search<_> = Search::new(&index, Alphabet::default());
+
+        for len in 0..32 {
+            search.search("C".repeat(len).as_bytes());
+        }
+        assert_eq!(search.get_hits(junctions[0]), Some(0));
+        assert_eq!(search.get_rc_hits(junctions[0]), Some(0));
+
+        search.search("C".repeat(32).as_bytes());
+        assert_eq!(search.get_hits(junctions[0]), Some(1));
+    }
+
+    #[test]
+    fn test_search_poly_a() {
+        // With two junctions the table holds 16 slots, so poly-A (key
+        // 0) and the junction with key 16 want the same one.
+        let junctions: Vec<CompressedSeq> = vec![
+            compress_seq(&"A".repeat(32), 32).unwrap(),
+            compress_seq(&format!("{}CAA", "A".repeat(29)), 32).unwrap(),
+        ];
+        assert_eq!(junctions, [0, 16]);
+
+        let index =
+            JunctionIndex::new(&junctions, 32, Strand::Forward).unwrap();
+
+        let mut search: Search<_> = Search::new(&index, Alphabet::default());
+        search.search(format!("{}CAA", "A".repeat(33)).as_bytes());
+        search.search("C".repeat(40).as_bytes());
+
+        assert_eq!(search.get_hits(junctions[0]), Some(2));
+        assert_eq!(search.get_hits(junctions[1]), Some(1));
+    }
+
+    #[test]
+    fn test_search_perfect() {
+        // Both backends count the same hits
+        let haystack = format!(
+            "{}N{}{}",
+            "ACGT".repeat(12),
+            "GATTACA".repeat(12),
+            "C".repeat(40),
+        );
+        let junctions: Vec<CompressedSeq> = haystack
+            .as_bytes()
+            .windows(21)
+            .step_by(3)
+            .filter_map(|window| {
+                compress_seq(std::str::from_utf8(window).unwrap(), 21)
+            })
+            .chain([0])
+            .collect();
+
+        for strand in [Strand::Forward, Strand::Both, Strand::Canonical] {
+            let index = JunctionIndex::new(&junctions, 21, strand).unwrap();
+            let mut hash: Search<_> =
+                Search::new(&index, Alphabet::default());
+            let index = JunctionIndex::new(&junctions, 21, strand).unwrap();
+            let mut perfect: Search<_, PerfectHash> =
+                Search::new(&index, Alphabet::default());
+            hash.search(haystack.as_bytes());
+            perfect.search(haystack.as_bytes());
+
+            for junction in &junctions {
+                let key = strand.junction_key(*junction, 21);
+                assert_eq!(hash.get_hits(key), perfect.get_hits(key));
+                assert_eq!(hash.get_rc_hits(key), perfect.get_rc_hits(key));
+            }
+        }
+    }
+
+    #[test]
+    fn test_search_prefilter() {
+        let junctions: Vec<CompressedSeq> = vec![
+            compress_seq(&"ACGT".repeat(8), 32).unwrap(),
+            compress_seq(&"G".repeat(32), 32).unwrap(),
+            compress_seq(&"A".repeat(32), 32).unwrap(),
+        ];
+        let haystack = format!(
+            "{}N{}{}N{}",
+            "ACGT".repeat(10),
+            "GATTACA".repeat(10),
+            "G".repeat(34),
+            "A".repeat(32)
+        );
+
+        for strand in [Strand::Forward, Strand::Both, Strand::Canonical] {
+            let index = JunctionIndex::new(&junctions, 32, strand).unwrap();
+            let mut plain: Search<_> =
+                Search::new(&index, Alphabet::default());
+            let index = JunctionIndex::new(&junctions, 32, strand)
+                .unwrap()
+                .with_prefilter(0.001);
+            let mut filtered: Search<_> =
+                Search::new(&index, Alphabet::default());
+            plain.search(haystack.as_bytes());
+            filtered.search(haystack.as_bytes());
+
+            // The filter only ever turns away windows that would miss
+            for junction in &junctions {
+                let key = strand.junction_key(*junction, 32);
+                assert_eq!(plain.get_hits(key), filtered.get_hits(key));
+                assert_eq!(plain.get_rc_hits(key), filtered.get_rc_hits(key));
+            }
+
+            assert_eq!(plain.lookups, filtered.lookups);
+            assert_eq!(plain.rejected, 0);
+            assert!(filtered.rejected > 0);
+            assert!(filtered.rejected < filtered.lookups);
+        }
+    }
+
+    /// Random junctions of length 20 and reads that each hold one of
+    /// them, on either strand, among random bases.
+    fn planted_reads(
+        seed: u64,
+        num_reads: usize,
+    ) -> (Vec<CompressedSeq>, Vec<String>) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut random_seq = |len: usize| -> String {
+            (0..len)
+                .map(|_| ['A', 'C', 'G', 'T'][rng.gen_range(0..4)])
+                .collect()
+        };
+
+        let junctions: Vec<String> =
+            (0..50).map(|_| random_seq(20)).collect();
+        let keys: Vec<CompressedSeq> = junctions
+            .iter()
+            .map(|junction| compress_seq(junction, 20).unwrap())
+            .collect();
+
+        let reads: Vec<String> = (0..num_reads)
+            .map(|i| {
+                let junction = &junctions[i % junctions.len()];
+                let junction = if i % 3 == 0 {
+                    junction
+                        .chars()
+                        .rev()
+                        .map(|base| match base {
+                            'A' => 'T',
+                            'C' => 'G',
+                            'G' => 'C',
+                            _ => 'A',
+                        })
+                        .collect()
+                } else {
+                    junction.to_string()
+                };
+                format!("{}{junction}{}", random_seq(i % 17), random_seq(9))
+            })
+            .collect();
+
+        (keys, reads)
+    }
+
+    #[test]
+    fn test_search_merge() {
+        let (keys, reads) = planted_reads(1, 200);
+
+        for strand in [Strand::Forward, Strand::Both, Strand::Canonical] {
+            let index = JunctionIndex::new(&keys, 20, strand)
+                .unwrap()
+                .with_prefilter(0.01);
+            let mut serial: Search<_> =
+                Search::new(&index, Alphabet::default());
+            for read in &reads {
+                serial.search(read.as_bytes());
+            }
+
+            // Split the reads unevenly, as the workers would
+            let mut merged: Search<_> =
+                Search::new(&index, Alphabet::default());
+            for share in reads.chunks(37) {
+                let mut search: Search<_> =
+                    Search::new(&index, Alphabet::default());
+                for read in share {
+                    search.search(read.as_bytes());
+                }
+                merged.merge(&search);
+            }
+
+            assert!(serial.entries().any(|(_, hits, _)| hits > 0));
+            assert_eq!(
+                serial.entries().collect::<Vec<_>>(),
+                merged.entries().collect::<Vec<_>>()
+            );
+            assert_eq!(serial.lookups, merged.lookups);
+            assert_eq!(serial.rejected, merged.rejected);
+        }
+    }
+
+    #[test]
+    fn test_search_shared_counts() {
+        // Every read many times over, so threads keep hitting the same
+        // junctions at once
+        let (keys, reads) = planted_reads(2, 1000);
+
+        for strand in [Strand::Forward, Strand::Both, Strand::Canonical] {
+            let index = JunctionIndex::new(&keys, 20, strand).unwrap();
+            let mut serial: Search<_> =
+                Search::new(&index, Alphabet::default());
+            for _ in 0..8 {
+                for read in &reads {
+                    serial.search(read.as_bytes());
+                }
+            }
+
+            let counts: AtomicCounts =
+                AtomicCounts::new(index.table.capacity());
+            thread::scope(|scope| {
+                for thread in 0..8 {
+                    let (index, counts, reads) = (&index, &counts, &reads);
+                    scope.spawn(move || {
+                        let mut search = Search::with_counts(
+                            index,
+                            Alphabet::default(),
+                            counts,
+                        );
+                        // Start each thread at a different read
+                        for read in reads
+                            .iter()
+                            .cycle()
+                            .skip(thread * 125)
+                            .take(reads.len())
+                        {
+                            search.search(read.as_bytes());
+                        }
+                    });
+                }
+            });
+
+            let shared: Search<_> = Search::with_counts(
+                &index,
+                Alphabet::default(),
+                counts.snapshot(),
+            );
+            assert!(serial.entries().any(|(_, hits, _)| hits > 0));
+            assert_eq!(
+                serial.entries().collect::<Vec<_>>(),
+                shared.entries().collect::<Vec<_>>()
+            );
+        }
+    }
 }
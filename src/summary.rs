@@ -0,0 +1,680 @@
+#[cfg(feature = "columnar")]
+use crate::columnar::{count_hits, read_table};
+use crate::{
+    error::{Error, OutputError, Result},
+    output::{csv_field, json_string, Format, FIELDS},
+};
+use clap::ValueEnum;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    iter::Peekable,
+    path::Path,
+    str::Chars,
+};
+
+/// The hits on each junction in the output of several counts added up,
+/// such as those of the lanes of one sample.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Merged {
+    /// The format every count was written in
+    pub format: Format,
+    /// The reads file of each count, or its sample in the tables
+    pub samples: Vec<String>,
+    /// The junctions, in the order they are first seen
+    pub names: Vec<String>,
+    /// The sequence of each junction, which only the tables have
+    pub seqs: Vec<String>,
+    /// The summed hits on each junction, column by column
+    pub rows: Vec<Vec<u64>>,
+    /// The reads in every count, which only the tables have
+    pub reads: u64,
+}
+
+impl Merged {
+    /// Writes the sums as the output of a single count of every reads
+    /// file, in the format they were written in.
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        let sample = self.samples.join(",");
+        match self.format {
+            Format::Legacy => writeln!(out, "File: {sample}")?,
+            Format::Tsv => writeln!(out, "{}", FIELDS.join("\t"))?,
+            Format::Csv => writeln!(out, "{}", FIELDS.join(","))?,
+            _ => {}
+        }
+
+        for ((name, seq), row) in
+            self.names.iter().zip(&self.seqs).zip(&self.rows)
+        {
+            let counts: Vec<String> = row
+                .iter()
+                .chain([&self.reads])
+                .map(|count| count.to_string())
+                .collect();
+            match self.format {
+                Format::Tsv => writeln!(
+                    out,
+                    "{sample}\t{name}\t{seq}\t{}",
+                    counts.join("\t")
+                )?,
+                Format::Csv => writeln!(
+                    out,
+                    "{},{},{},{}",
+                    csv_field(&sample),
+                    csv_field(name),
+                    csv_field(seq),
+                    counts.join(",")
+                )?,
+                Format::Jsonl => {
+                    let strings =
+                        [&sample, name, seq].map(|s| json_string(s));
+                    let fields: Vec<String> = FIELDS
+                        .iter()
+                        .zip(strings.iter().chain(&counts))
+                        .map(|(field, value)| format!(r#""{field}":{value}"#))
+                        .collect();
+                    writeln!(out, "{{{}}}", fields.join(","))?;
+                }
+                // Anything else is the legacy name and hits
+                _ => {
+                    write!(out, "{name}")?;
+                    for hits in row {
+                        write!(out, "\t{hits}")?;
+                    }
+                    writeln!(out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adds up the hits on each junction in `files`, written by counts with
+/// the same options in the same text format, which is told by the
+/// extension. The tables sum the reads given on their rows, or for a
+/// count that wrote no rows, in the `.count` file written beside it.
+pub fn merge_counts(files: &[impl AsRef<str>]) -> Result<Merged> {
+    let mut merged = Merged::default();
+    let mut lookup: HashMap<String, usize> = HashMap::new();
+
+    for (num, filename) in files.iter().enumerate() {
+        let filename = filename.as_ref();
+        let malformed = |source| Error::Output {
+            path: filename.to_string(),
+            source,
+        };
+        let format = text_format(Path::new(filename)).map_err(malformed)?;
+        if num == 0 {
+            merged.format = format;
+        } else if format != merged.format {
+            return Err(malformed(OutputError::Formats));
+        }
+
+        let contents =
+            fs::read_to_string(filename).map_err(|source| Error::Io {
+                path: filename.to_string(),
+                source,
+            })?;
+        let counts = read_counts(&contents, format).map_err(malformed)?;
+        merged.samples.push(counts.sample.unwrap_or_else(|| {
+            // A table with no rows has only its name to go by
+            let stem = Path::new(filename).file_stem().unwrap_or_default();
+            stem.to_string_lossy().to_string()
+        }));
+        merged.reads += match counts.reads {
+            Some(reads) => reads,
+            None if format == Format::Legacy => 0,
+            None => count_reads(Path::new(filename))?,
+        };
+
+        for (name, seq, hits) in counts.rows {
+            let row = *lookup.entry(name.clone()).or_insert_with(|| {
+                merged.names.push(name.clone());
+                merged.seqs.push(seq);
+                merged.rows.push(vec![0; hits.len()]);
+                merged.rows.len() - 1
+            });
+            // Every file must come from a count with the same columns
+            let expected = merged.rows[0].len();
+            if hits.len() != expected {
+                return Err(malformed(OutputError::Columns {
+                    name,
+                    found: hits.len(),
+                    expected,
+                }));
+            }
+
+            for (total, hits) in merged.rows[row].iter_mut().zip(hits) {
+                *total = total.saturating_add(hits);
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// The reads in a count, from the `.count` file written beside its
+/// output at `path`.
+fn count_reads(path: &Path) -> Result<u64> {
+    let count_path = path.with_extension("count");
+    let path = count_path.display().to_string();
+    let contents = match fs::read_to_string(&count_path) {
+        Ok(contents) => contents,
+        Err(source) => return Err(Error::Io { path, source }),
+    };
+    contents.trim().parse().map_err(|source| Error::Output {
+        path,
+        source: OutputError::Count {
+            name: "reads".to_string(),
+            source,
+        },
+    })
+}
+
+/// The number of junctions with any hits in a file written by
+/// `write_counts`, which may have rows with none as well.
+fn junctions_hit(path: &Path, format: Format) -> Result<usize> {
+    let io_error = |source| Error::Io {
+        path: path.display().to_string(),
+        source,
+    };
+    #[cfg(feature = "columnar")]
+    if let Some(columnar) = format.columnar() {
+        let batches = read_table(path, columnar).map_err(io_error)?;
+        return Ok(count_hits(&batches));
+    }
+
+    let contents = fs::read_to_string(path).map_err(io_error)?;
+    let counts =
+        read_counts(&contents, format).map_err(|source| Error::Output {
+            path: path.display().to_string(),
+            source,
+        })?;
+    Ok(counts
+        .rows
+        .iter()
+        .filter(|(_, _, hits)| hits.iter().any(|hits| *hits > 0))
+        .count())
+}
+
+/// The text format a count output at `path` was written in, by its
+/// extension.
+fn text_format(path: &Path) -> Result<Format, OutputError> {
+    let extension = path.extension().unwrap_or_default();
+    match Format::value_variants()
+        .iter()
+        .find(|format| extension == format.extension())
+    {
+        Some(Format::Legacy) | None => Ok(Format::Legacy),
+        Some(Format::Tsv) => Ok(Format::Tsv),
+        Some(Format::Csv) => Ok(Format::Csv),
+        Some(Format::Jsonl) => Ok(Format::Jsonl),
+        #[cfg(feature = "columnar")]
+        Some(_) => Err(OutputError::NotText),
+    }
+}
+
+/// The rows of one count output, each a junction name, its sequence
+/// and its hits.
+struct Counts {
+    sample: Option<String>,
+    /// The reads given on every row of a table, if it has any
+    reads: Option<u64>,
+    rows: Vec<(String, String, Vec<u64>)>,
+}
+
+/// Reads back what `write_counts` wrote in a text `format`.
+fn read_counts(
+    contents: &str,
+    format: Format,
+) -> Result<Counts, OutputError> {
+    let mut counts = Counts {
+        sample: None,
+        reads: None,
+        rows: vec![],
+    };
+    let mut lines = contents.lines();
+    let parse = |name: &str, value: &str| {
+        value.parse::<u64>().map_err(|source| OutputError::Count {
+            name: name.to_string(),
+            source,
+        })
+    };
+
+    if format == Format::Legacy {
+        match lines.next().and_then(|line| line.strip_prefix("File: ")) {
+            Some(reads_file) => counts.sample = Some(reads_file.to_string()),
+            _ => return Err(OutputError::NotCounts),
+        }
+        for line in lines {
+            let mut fields = line.split('\t');
+            let name = fields.next().unwrap_or_default();
+            let hits = fields
+                .map(|field| parse(name, field))
+                .collect::<Result<_, _>>()?;
+            counts.rows.push((name.to_string(), String::new(), hits));
+        }
+        return Ok(counts);
+    }
+
+    let header = match format {
+        Format::Tsv => Some(FIELDS.join("\t")),
+        Format::Csv => Some(FIELDS.join(",")),
+        _ => None,
+    };
+    if let Some(header) = header {
+        if lines.next() != Some(header.as_str()) {
+            return Err(OutputError::NotCounts);
+        }
+    }
+
+    for line in lines {
+        let fields = match format {
+            Format::Tsv => Some(line.split('\t').map(String::from).collect()),
+            Format::Csv => csv_fields(line),
+            _ => json_fields(line),
+        };
+        let Some([sample, name, seq, hits @ ..]) = fields
+            .as_deref()
+            .filter(|fields| fields.len() == FIELDS.len())
+        else {
+            return Err(OutputError::NotCounts);
+        };
+        let mut hits = hits
+            .iter()
+            .map(|field| parse(name, field))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Every row has the reads of the whole count
+        counts.reads = hits.pop();
+        counts.sample = Some(sample.clone());
+        counts.rows.push((name.clone(), seq.clone(), hits));
+    }
+    Ok(counts)
+}
+
+/// The fields of a line of CSV, unquoted.
+fn csv_fields(line: &str) -> Option<Vec<String>> {
+    let mut fields = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next()? {
+                    '"' if chars.next_if_eq(&'"').is_none() => break,
+                    chr => field.push(chr),
+                }
+            }
+        }
+        while let Some(chr) = chars.next_if(|chr| *chr != ',') {
+            field.push(chr);
+        }
+        fields.push(field);
+        if chars.next().is_none() {
+            return Some(fields);
+        }
+    }
+}
+
+/// The values of a line of JSON Lines written by `write_counts`, with
+/// the names of its fields checked. Strings are unescaped and numbers
+/// are left as they are.
+fn json_fields(line: &str) -> Option<Vec<String>> {
+    let mut values = vec![];
+    let mut chars = line.chars().peekable();
+    chars.next_if_eq(&'{')?;
+    for (num, field) in FIELDS.iter().enumerate() {
+        if num > 0 {
+            chars.next_if_eq(&',')?;
+        }
+        if json_str(&mut chars)? != *field {
+            return None;
+        }
+        chars.next_if_eq(&':')?;
+        let value = if chars.peek() == Some(&'"') {
+            json_str(&mut chars)?
+        } else {
+            let mut number = String::new();
+            while let Some(chr) = chars.next_if(char::is_ascii_digit) {
+                number.push(chr);
+            }
+            number
+        };
+        values.push(value);
+    }
+    chars.next_if_eq(&'}')?;
+    chars.next().is_none().then_some(values)
+}
+
+/// A JSON string, unescaped.
+fn json_str(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut value = String::new();
+    chars.next_if_eq(&'"')?;
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                'u' => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    let code = u32::from_str_radix(&code, 16).ok()?;
+                    value.push(char::from_u32(code)?);
+                }
+                chr => value.push(chr),
+            },
+            chr => value.push(chr),
+        }
+    }
+}
+
+/// The reads and the junctions with any hits in one reads file, from
+/// the files a count wrote for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleStats {
+    pub sample: String,
+    pub reads: u64,
+    pub short_reads: u64,
+    pub junctions: usize,
+}
+
+/// The stats of every reads file counted into `outdir`, by name.
+pub fn read_stats(outdir: &Path) -> Result<Vec<SampleStats>> {
+    let io_error = |path: &Path| {
+        let path = path.display().to_string();
+        move |source| Error::Io { path, source }
+    };
+    let mut stats_files: Vec<_> = fs::read_dir(outdir)
+        .map_err(io_error(outdir))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "stats"))
+        .collect();
+    stats_files.sort();
+
+    if stats_files.is_empty() {
+        return Err(Error::NoCounts(outdir.display().to_string()));
+    }
+
+    let mut samples = vec![];
+    for path in stats_files {
+        let malformed = |source| Error::Output {
+            path: path.display().to_string(),
+            source,
+        };
+        let sample = path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let contents = fs::read_to_string(&path).map_err(io_error(&path))?;
+        let stats: HashMap<_, _> = contents
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .collect();
+        let stat = |name: &str| match stats.get(name) {
+            Some(value) => value.parse().map_err(|source| {
+                malformed(OutputError::Count {
+                    name: name.to_string(),
+                    source,
+                })
+            }),
+            _ => Ok(0),
+        };
+
+        // The hits may have been written in any format
+        let (format, hits_path) = Format::value_variants()
+            .iter()
+            .map(|format| {
+                let extension = format.extension();
+                (*format, outdir.join(format!("{sample}.{extension}")))
+            })
+            .find(|(_, path)| path.exists())
+            .ok_or_else(|| malformed(OutputError::NoHits))?;
+        let junctions = junctions_hit(&hits_path, format)?;
+
+        samples.push(SampleStats {
+            reads: stat("reads")?,
+            short_reads: stat("short_reads")?,
+            sample,
+            junctions,
+        });
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        counter::{Counter, Stats, Tallies, Tally},
+        error::{Error, OutputError},
+        output::FIELDS,
+        output::{write_counts, Format, Selection},
+        search::Strand,
+        summary::{merge_counts, read_stats, Merged, SampleStats},
+    };
+    use std::fs::{self, File};
+    use tempfile::TempDir;
+
+    #[test]
+    fn summary_merge() {
+        let dir = TempDir::new().unwrap();
+        let files = [
+            ("one.txt", "File: a.fa\nx\t1\t2\ny\t3\t4\n"),
+            ("two.txt", "File: b.fa\ny\t10\t20\nz\t5\t6\n"),
+            ("odd.txt", "File: c.fa\nx\t1\n"),
+            ("bad.txt", "x\t1\t2\n"),
+        ]
+        .map(|(name, contents)| {
+            let path = dir.path().join(name);
+            fs::write(&path, contents).unwrap();
+            path.to_string_lossy().to_string()
+        });
+
+        let merged = merge_counts(&files[..2]).unwrap();
+        assert_eq!(
+            merged,
+            Merged {
+                format: Format::Legacy,
+                samples: vec!["a.fa".to_string(), "b.fa".to_string()],
+                names: ["x", "y", "z"].map(String::from).to_vec(),
+                seqs: vec![String::new(); 3],
+                rows: vec![vec![1, 2], vec![13, 24], vec![5, 6]],
+                reads: 0,
+            }
+        );
+        let mut out = vec![];
+        merged.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "File: a.fa,b.fa\nx\t1\t2\ny\t13\t24\nz\t5\t6\n"
+        );
+
+        let res = merge_counts(&files[..3]);
+        assert!(matches!(
+            res,
+            Err(Error::Output {
+                source: OutputError::Columns {
+                    found: 1,
+                    expected: 2,
+                    ..
+                },
+                ..
+            })
+        ));
+
+        let res = merge_counts(&files[3..]);
+        assert!(matches!(
+            res,
+            Err(Error::Output {
+                source: OutputError::NotCounts,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn summary_merge_tables() {
+        let dir = TempDir::new().unwrap();
+        let path =
+            |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        let tally = Tally {
+            exact: (3, 1),
+            approx: (0, 2),
+        };
+        let counter = Counter::builder()
+            .kmer_size(4)
+            .strand(Strand::Both)
+            .junctions([("one, \"1\"", "ACGT"), ("two\\2", "TTTT")])
+            .unwrap();
+        let lane = |reads| Tallies {
+            tallies: vec![tally, Tally::default()],
+            stats: Stats {
+                reads,
+                ..Stats::default()
+            },
+        };
+        let selection = Selection {
+            report_zeros: true,
+            ..Selection::default()
+        };
+
+        for format in [Format::Tsv, Format::Csv, Format::Jsonl] {
+            let extension = format.extension();
+            let mut files = vec![];
+            for (sample, reads) in [("a.fa", 10), ("b.fa", 5)] {
+                let filename = path(&format!("{sample}.{extension}"));
+                let mut out = File::create(&filename).unwrap();
+                let tallies = lane(reads);
+                write_counts(
+                    &mut out, format, selection, sample, sample, &counter,
+                    &tallies,
+                )
+                .unwrap();
+                files.push(filename);
+            }
+
+            // Every hit is doubled, as if counted in one file
+            let merged = merge_counts(&files).unwrap();
+            assert_eq!(merged.format, format);
+            assert_eq!(merged.samples, ["a.fa", "b.fa"]);
+            assert_eq!(merged.names, counter.names());
+            assert_eq!(merged.seqs, counter.seqs());
+            assert_eq!(merged.rows, [[6, 2, 8, 0, 4, 4], [0; 6]]);
+            assert_eq!(merged.reads, 15);
+
+            let mut out = vec![];
+            merged.write(&mut out).unwrap();
+            let tallies = Tallies {
+                tallies: vec![
+                    Tally {
+                        exact: (6, 2),
+                        approx: (0, 4),
+                    },
+                    Tally::default(),
+                ],
+                stats: Stats {
+                    reads: 15,
+                    ..Stats::default()
+                },
+            };
+            let mut expected = vec![];
+            write_counts(
+                &mut expected,
+                format,
+                selection,
+                "",
+                "a.fa,b.fa",
+                &counter,
+                &tallies,
+            )
+            .unwrap();
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                String::from_utf8(expected).unwrap(),
+                "{format:?}"
+            );
+        }
+
+        // A table with no rows has its reads in the count beside it
+        let selection = Selection {
+            min_count: Some(100),
+            ..Selection::default()
+        };
+        let mut out = File::create(path("c.fa.tsv")).unwrap();
+        write_counts(
+            &mut out,
+            Format::Tsv,
+            selection,
+            "c.fa",
+            "c.fa",
+            &counter,
+            &lane(7),
+        )
+        .unwrap();
+        let files = [path("a.fa.tsv"), path("c.fa.tsv")];
+        assert!(matches!(merge_counts(&files), Err(Error::Io { .. })));
+        fs::write(path("c.fa.count"), "7\n").unwrap();
+        let merged = merge_counts(&files).unwrap();
+        assert_eq!(merged.samples, ["a.fa", "c.fa"]);
+        assert_eq!(merged.rows, [[3, 1, 4, 0, 2, 2], [0; 6]]);
+        assert_eq!(merged.reads, 17);
+
+        // Only counts of one format add up
+        let res = merge_counts(&[path("a.fa.tsv"), path("a.fa.csv")]);
+        assert!(matches!(
+            res,
+            Err(Error::Output {
+                source: OutputError::Formats,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn summary_stats() {
+        let dir = TempDir::new().unwrap();
+        assert!(matches!(read_stats(dir.path()), Err(Error::NoCounts(_))));
+
+        // Only the junctions with hits are counted, not rows of zeros
+        let header = FIELDS.join("\t");
+        let b_rows = "b.fa\tx\tACGT\t0\t0\t0\t0\t1\t1\t5\n\
+            b.fa\ty\tTTTT\t2\t0\t2\t0\t0\t0\t5\n\
+            b.fa\tz\tGGGG\t0\t0\t0\t0\t0\t0\t5\n";
+        for (name, contents) in [
+            ("b.fa.stats", "reads\t5\nshort_reads\t1\n"),
+            ("b.fa.tsv", &format!("{header}\n{b_rows}")),
+            ("a.fa.stats", "reads\t3\nshort_reads\t0\n"),
+            ("a.fa.txt", "File: a.fa\nx\t1\ny\t0\n"),
+        ] {
+            fs::write(dir.path().join(name), contents).unwrap();
+        }
+        assert_eq!(
+            read_stats(dir.path()).unwrap(),
+            [
+                SampleStats {
+                    sample: "a.fa".to_string(),
+                    reads: 3,
+                    short_reads: 0,
+                    junctions: 1,
+                },
+                SampleStats {
+                    sample: "b.fa".to_string(),
+                    reads: 5,
+                    short_reads: 1,
+                    junctions: 2,
+                },
+            ]
+        );
+
+        fs::write(dir.path().join("c.fa.stats"), "reads\t2\n").unwrap();
+        assert!(matches!(
+            read_stats(dir.path()),
+            Err(Error::Output {
+                source: OutputError::NoHits,
+                ..
+            })
+        ));
+    }
+}
@@ -10,9 +10,20 @@ use tempfile::TempDir;
 const PRG: &str = "tallyman";
 const DNA_FA: &str = "tests/inputs/dna.fasta";
 const DNA_FQ: &str = "tests/inputs/dna.fastq";
+const KMER21_FA: &str = "tests/inputs/kmer21.fa";
+const KMER40_FA: &str = "tests/inputs/kmer40.fa";
+const MIXED_FA: &str = "tests/inputs/mixed.fa";
+const OVER_LONG_FA: &str = "tests/inputs/over_long.fa";
+const RNA_FA: &str = "tests/inputs/rna.fa";
+const RNA_URACIL_FA: &str = "tests/inputs/rna-uracil.fa";
+const KMER21_URACIL_FA: &str = "tests/inputs/kmer21-uracil.fa";
+const STRANDS_FA: &str = "tests/inputs/strands.fa";
+const AMBIGUOUS_FA: &str = "tests/inputs/ambiguous.fa";
+const MISMATCH_FA: &str = "tests/inputs/mismatch.fa";
+const TOO_SHORT_FA: &str = "tests/inputs/too_short.fa";
 
 const RNA_FA_50K: &str = "tests/inputs/rna-50k.fasta";
-const RNA_FQ_50K: &str = "tests/inputs/rna-50k.fastq";
+const RNA_FQ_50K: &str = "tests/inputs/rna-50k.fastq.gz";
 const RNA_FA_100K: &str = "tests/inputs/rna-100k.fasta";
 const RNA_FQ_100K: &str = "tests/inputs/rna-100k.fastq";
 
@@ -21,9 +32,9 @@ const OUT_FA_100K: &str = "tests/outputs/rna-100k.fasta.txt";
 const OUT_FA_50K_COUNT: &str = "tests/outputs/rna-50k.fasta.count";
 const OUT_FA_100K_COUNT: &str = "tests/outputs/rna-100k.fasta.count";
 
-const OUT_FQ_50K: &str = "tests/outputs/rna-50k.fastq.txt";
+const OUT_FQ_50K: &str = "tests/outputs/rna-50k.fastq.gz.txt";
 const OUT_FQ_100K: &str = "tests/outputs/rna-100k.fastq.txt";
-const OUT_FQ_50K_COUNT: &str = "tests/outputs/rna-50k.fastq.count";
+const OUT_FQ_50K_COUNT: &str = "tests/outputs/rna-50k.fastq.gz.count";
 const OUT_FQ_100K_COUNT: &str = "tests/outputs/rna-100k.fastq.count";
 
 // --------------------------------------------------
@@ -47,7 +58,7 @@ fn dies_bad_reads_file() -> Result<()> {
     let bad = gen_bad_file();
     let expected = format!("{bad}: .* [(]os error 2[)]");
     Command::cargo_bin(PRG)?
-        .args(["-r", &bad, "-j", DNA_FA])
+        .args(["count", "-r", &bad, "-j", DNA_FA])
         .assert()
         .failure()
         .stderr(predicate::str::is_match(expected)?);
@@ -60,13 +71,28 @@ fn dies_bad_junction_file() -> Result<()> {
     let bad = gen_bad_file();
     let expected = format!("{bad}: .* [(]os error 2[)]");
     Command::cargo_bin(PRG)?
-        .args(["-j", &bad, "-r", RNA_FA_50K])
+        .args(["count", "-j", &bad, "-r", RNA_FA_50K])
         .assert()
         .failure()
         .stderr(predicate::str::is_match(expected)?);
     Ok(())
 }
 
+// --------------------------------------------------
+#[test]
+fn dies_bad_kmer_size() -> Result<()> {
+    for bad in ["0", "65", "foo"] {
+        let expected =
+            format!(r#"kmer size "{bad}" must be between 1 and 64"#);
+        Command::cargo_bin(PRG)?
+            .args(["count", "-j", DNA_FA, "-r", RNA_FA_50K, "-k", bad])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(expected));
+    }
+    Ok(())
+}
+
 // --------------------------------------------------
 fn run(
     read_files: &[&str],
@@ -78,6 +104,7 @@ fn run(
     let outdir = TempDir::new()?;
 
     let mut args: Vec<String> = vec![
+        "count".to_string(),
         "-j".to_string(),
         junction_file.to_string(),
         "-o".to_string(),
@@ -104,9 +131,11 @@ fn run(
         let outpath = &outdir.path().join(&data_basename);
         assert!(outpath.exists());
 
+        // The fixtures hold the junctions in the order of an old hash
+        // table rather than of the junctions file
         let expected = fs::read_to_string(expected_file)?;
         let actual = fs::read_to_string(outpath)?;
-        assert_eq!(&actual, &expected);
+        assert_eq!(sorted_lines(&actual), sorted_lines(&expected));
 
         let mut count_basename = read_base.clone();
         count_basename.push(".count");
@@ -120,6 +149,17 @@ fn run(
     Ok(())
 }
 
+// --------------------------------------------------
+// Returns the "File:" line of an output file followed by the rest of
+// its lines sorted.
+fn sorted_lines(contents: &str) -> Vec<&str> {
+    let mut lines: Vec<_> = contents.lines().collect();
+    if !lines.is_empty() {
+        lines[1..].sort_unstable();
+    }
+    lines
+}
+
 // --------------------------------------------------
 #[test]
 fn run_50k_fasta() -> Result<()> {
@@ -154,3 +194,945 @@ fn run_50k_100k_fastq() -> Result<()> {
         &[OUT_FA_50K_COUNT, OUT_FQ_100K_COUNT],
     )
 }
+
+// --------------------------------------------------
+// Runs a small FASTA `read_file` against `junction_file` and returns
+// the sorted junction lines of its output along with STDERR.
+fn run_small(
+    read_file: &str,
+    junction_file: &str,
+    opts: &[&str],
+) -> Result<(Vec<String>, String)> {
+    run_small_against(read_file, &["-j", junction_file], opts)
+}
+
+// --------------------------------------------------
+// Like `run_small`, with the junctions given by `junction_opts`.
+fn run_small_against(
+    read_file: &str,
+    junction_opts: &[&str],
+    opts: &[&str],
+) -> Result<(Vec<String>, String)> {
+    let outdir = TempDir::new()?;
+    let outdir_name = outdir.path().to_string_lossy().to_string();
+    let output = Command::cargo_bin(PRG)?
+        .arg("count")
+        .args(junction_opts)
+        .args(["-r", read_file, "-o", &outdir_name])
+        .args(opts)
+        .output()?;
+    assert!(output.status.success());
+
+    let read_base = Path::new(read_file)
+        .file_name()
+        .ok_or(anyhow!("No basename"))?
+        .to_string_lossy()
+        .to_string();
+    let outpath = outdir.path().join(format!("{read_base}.txt"));
+    let actual = fs::read_to_string(outpath)?;
+    let mut lines: Vec<_> = actual.lines().map(str::to_string).collect();
+    assert_eq!(lines.remove(0), format!("File: {read_file}"));
+    lines.sort();
+
+    let outpath = outdir.path().join(format!("{read_base}.count"));
+    let actual = fs::read_to_string(outpath)?;
+    let num_reads = fs::read_to_string(read_file)?.matches('>').count();
+    assert_eq!(actual, format!("{num_reads}\n"));
+
+    Ok((lines, String::from_utf8(output.stderr)?))
+}
+
+// --------------------------------------------------
+#[test]
+fn run_kmer_size_21() -> Result<()> {
+    let (lines, stderr) = run_small(RNA_FA, KMER21_FA, &["-k", "21"])?;
+    assert_eq!(lines, ["acgt\t6", "polyC\t24"]);
+    assert!(stderr.contains("(too_long) rejected: length 32 is not 21"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_wide_kmers() -> Result<()> {
+    let (lines, stderr) = run_small(RNA_FA, KMER40_FA, &["-k", "40"])?;
+    assert_eq!(lines, ["long_acgt\t1", "long_polyc\t2"]);
+    assert!(stderr.contains("(short) rejected"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_mixed_lengths() -> Result<()> {
+    // Each junction is matched at its own length, just as it would be
+    // on its own, wherever it is in the file
+    let (lines, stderr) = run_small(RNA_FA, MIXED_FA, &[])?;
+    let mut expected = [
+        "polyC\t24",
+        "acgt\t6",
+        "short\t3",
+        "long_acgt\t1",
+        "long_polyc\t2",
+    ];
+    expected.sort();
+    assert_eq!(lines, expected);
+    assert!(stderr.contains("(too_long) rejected: length 70"));
+
+    for opts in [
+        &["--shared-counts"][..],
+        &["--backend", "perfect"],
+        &["--prefilter"],
+    ] {
+        assert_eq!(run_small(RNA_FA, MIXED_FA, opts)?.0, expected);
+    }
+    assert_eq!(
+        read_stats(RNA_FA, MIXED_FA, &[])?,
+        "reads\t3\nshort_reads\t0\n"
+    );
+
+    // Unless one length is asked for
+    let (lines, _) = run_small(RNA_FA, MIXED_FA, &["-k", "21"])?;
+    assert_eq!(lines, ["acgt\t6", "polyC\t24"]);
+
+    Command::cargo_bin(PRG)?
+        .args(["validate", "-j", MIXED_FA])
+        .assert()
+        .failure()
+        .stdout(predicate::str::starts_with(
+            "kmer_size\t21,32,40\njunctions\t5\n",
+        ));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_strands() -> Result<()> {
+    let (lines, _) = run_small(RNA_FA, STRANDS_FA, &["-s", "forward"])?;
+    assert_eq!(lines, ["polyC\t24"]);
+
+    // Other modes report forward, reverse complement and total hits
+    let (lines, _) = run_small(RNA_FA, STRANDS_FA, &["-s", "reverse"])?;
+    assert_eq!(lines, ["polyG\t0\t24\t24"]);
+
+    let (lines, _) = run_small(RNA_FA, STRANDS_FA, &["-s", "both"])?;
+    assert_eq!(lines, ["polyC\t24\t0\t24", "polyG\t0\t24\t24"]);
+
+    // The two junctions are the same canonical k-mer, and the strands
+    // are still reported relative to the junction as given.
+    let (lines, stderr) =
+        run_small(RNA_FA, STRANDS_FA, &["-s", "canonical"])?;
+    assert_eq!(lines, ["polyG\t0\t24\t24"]);
+    assert!(stderr.contains("(polyC) duplicated"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_uracil() -> Result<()> {
+    // U is read as T in both the reads and the junctions
+    let (lines, _) = run_small(RNA_URACIL_FA, KMER21_FA, &["-k", "21"])?;
+    assert_eq!(lines, ["acgt\t6", "polyC\t24"]);
+
+    let (lines, _) = run_small(RNA_FA, KMER21_URACIL_FA, &[])?;
+    assert_eq!(lines, ["acgu\t6", "polyC\t24"]);
+
+    let (lines, _) = run_small(RNA_URACIL_FA, KMER21_URACIL_FA, &[])?;
+    assert_eq!(lines, ["acgu\t6", "polyC\t24"]);
+
+    // Unless it is turned off
+    let (lines, _) =
+        run_small(RNA_URACIL_FA, KMER21_FA, &["-k", "21", "--no-uracil"])?;
+    assert_eq!(lines, ["polyC\t24"]);
+
+    let (lines, stderr) =
+        run_small(RNA_FA, KMER21_URACIL_FA, &["--no-uracil"])?;
+    assert_eq!(lines, ["polyC\t24"]);
+    assert!(stderr.contains("(acgu) rejected"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_ambiguous() -> Result<()> {
+    let (lines, stderr) = run_small(RNA_FA, AMBIGUOUS_FA, &[])?;
+    assert_eq!(lines, ["acgt\t6"]);
+    for name in ["polyC_N", "cgy", "too_many"] {
+        assert!(stderr.contains(&format!("({name}) rejected")));
+    }
+
+    // Each expanded k-mer is counted toward its junction
+    let (lines, stderr) =
+        run_small(RNA_FA, AMBIGUOUS_FA, &["--ambiguous", "expand"])?;
+    assert_eq!(lines, ["acgt\t6", "cgy\t6", "polyC_N\t24"]);
+    assert!(stderr.contains("(too_many) rejected: more than 256 expansions"));
+
+    let (lines, stderr) = run_small(
+        RNA_FA,
+        AMBIGUOUS_FA,
+        &["--ambiguous", "expand", "--max-expansions", "2"],
+    )?;
+    assert_eq!(lines, ["acgt\t6", "cgy\t6"]);
+    assert!(stderr.contains("(polyC_N) rejected: more than 2 expansions"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_mismatches() -> Result<()> {
+    // Approximate hits are reported after the exact hits
+    let (lines, _) = run_small(RNA_FA, MISMATCH_FA, &["-m", "1"])?;
+    assert_eq!(lines, ["acgt\t6\t0", "polyC_A\t0\t24"]);
+
+    let (lines, _) = run_small(RNA_FA, MISMATCH_FA, &["-m", "2"])?;
+    assert_eq!(lines, ["acgt\t6\t0", "polyC_A\t0\t28"]);
+
+    let (lines, _) =
+        run_small(RNA_FA, MISMATCH_FA, &["-m", "1", "-s", "both"])?;
+    assert_eq!(
+        lines,
+        ["acgt\t6\t6\t12\t0\t1\t1", "polyC_A\t0\t0\t0\t24\t0\t24"]
+    );
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_mismatches() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["count", "-j", DNA_FA, "-r", RNA_FA, "-m", "3"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("3 is not in 0..=2"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_short_reads() -> Result<()> {
+    // Both reads are 28 bases, too short for the 32-base junctions
+    let (lines, _) = run_small(TOO_SHORT_FA, DNA_FA, &[])?;
+    assert!(lines.is_empty());
+    assert_eq!(
+        read_stats(TOO_SHORT_FA, DNA_FA, &[])?,
+        "reads\t2\nshort_reads\t2\n"
+    );
+
+    let (lines, _) = run_small(TOO_SHORT_FA, KMER40_FA, &["-s", "both"])?;
+    assert!(lines.is_empty());
+    assert_eq!(
+        read_stats(TOO_SHORT_FA, KMER40_FA, &[])?,
+        "reads\t2\nshort_reads\t2\n"
+    );
+
+    // Neither is too short for 21-mers
+    let (lines, _) = run_small(TOO_SHORT_FA, KMER21_FA, &[])?;
+    assert_eq!(lines, ["polyC\t8"]);
+    assert_eq!(
+        read_stats(TOO_SHORT_FA, KMER21_FA, &[])?,
+        "reads\t2\nshort_reads\t0\n"
+    );
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_many_hits() -> Result<()> {
+    // One junction is hit more times than fit in an i16
+    let dir = TempDir::new()?;
+    let reads = dir.path().join("many.fa");
+    let reads_name = reads.to_string_lossy().to_string();
+    let hits = i16::MAX as usize + 100;
+    fs::write(&reads, format!(">many\n{}\n", "C".repeat(hits + 20)))?;
+
+    for opts in [&[][..], &["--wide-counts"]] {
+        let (lines, _) = run_small(&reads_name, KMER21_FA, opts)?;
+        assert_eq!(lines, [format!("polyC\t{hits}")]);
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+// Runs `read_file` against `junction_file` and returns its statistics.
+fn read_stats(
+    read_file: &str,
+    junction_file: &str,
+    opts: &[&str],
+) -> Result<String> {
+    let outdir = TempDir::new()?;
+    let outdir_name = outdir.path().to_string_lossy().to_string();
+    Command::cargo_bin(PRG)?
+        .args([
+            "count",
+            "-j",
+            junction_file,
+            "-r",
+            read_file,
+            "-o",
+            &outdir_name,
+        ])
+        .args(opts)
+        .assert()
+        .success();
+
+    let read_base = Path::new(read_file)
+        .file_name()
+        .ok_or(anyhow!("No basename"))?
+        .to_string_lossy()
+        .to_string();
+    let outpath = outdir.path().join(format!("{read_base}.stats"));
+    Ok(fs::read_to_string(outpath)?)
+}
+
+// --------------------------------------------------
+#[test]
+fn run_backends() -> Result<()> {
+    // The perfect hash counts exactly what the hash table does
+    for (read_file, junction_file, opts) in [
+        (RNA_FA, KMER21_FA, &[][..]),
+        (RNA_FA, KMER40_FA, &["-s", "both"]),
+        (RNA_FA, STRANDS_FA, &["-s", "canonical"]),
+        (RNA_FA, MISMATCH_FA, &["-m", "2", "--wide-counts"]),
+        (RNA_FA_50K, DNA_FA, &[]),
+    ] {
+        let (expected, _) = run_small(read_file, junction_file, opts)?;
+        let opts = [opts, &["--backend", "perfect"]].concat();
+        let (actual, _) = run_small(read_file, junction_file, &opts)?;
+        assert_eq!(actual, expected);
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_index() -> Result<()> {
+    // Counting against a saved index is just like reading the junctions
+    let indir = TempDir::new()?;
+    for (num, (junction_file, opts)) in [
+        (KMER21_FA, &["-k", "21"][..]),
+        (KMER40_FA, &["-s", "both"]),
+        (STRANDS_FA, &["-s", "canonical", "--backend", "perfect"]),
+        (MISMATCH_FA, &["-m", "2"]),
+        (AMBIGUOUS_FA, &["--ambiguous", "expand"]),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let index = indir.path().join(format!("{num}.idx"));
+        let index = index.to_string_lossy().to_string();
+        Command::cargo_bin(PRG)?
+            .args(["index", "-j", junction_file, "-o", &index])
+            .args(opts)
+            .assert()
+            .success();
+
+        let (expected, _) = run_small(RNA_FA, junction_file, opts)?;
+        let (actual, _) =
+            run_small_against(RNA_FA, &["--index", &index], &[])?;
+        assert_eq!(actual, expected);
+
+        // Options that only affect the counting still apply
+        let opts = ["--wide-counts", "--prefilter"];
+        let (actual, _) =
+            run_small_against(RNA_FA, &["--index", &index], &opts)?;
+        assert_eq!(actual, expected);
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_index_over_long() -> Result<()> {
+    // A junction too long for any k-mer is left out, and does not
+    // change how the k-mers of the rest are saved
+    let indir = TempDir::new()?;
+    let index = indir.path().join("over_long.idx");
+    let index = index.to_string_lossy().to_string();
+    Command::cargo_bin(PRG)?
+        .args(["index", "-j", OVER_LONG_FA, "-o", &index])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("(too_long) rejected"));
+
+    let (expected, stderr) = run_small(RNA_FA, OVER_LONG_FA, &[])?;
+    assert_eq!(expected, ["acgt\t6", "polyC\t24"]);
+    assert!(stderr.contains("(too_long) rejected: length 72"));
+    let (actual, _) = run_small_against(RNA_FA, &["--index", &index], &[])?;
+    assert_eq!(actual, expected);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_index() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["count", "--index", KMER21_FA, "-r", RNA_FA])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "{KMER21_FA}: not a tallyman index"
+        )));
+
+    // The index already settles how the junctions are read
+    Command::cargo_bin(PRG)?
+        .args(["count", "--index", KMER21_FA, "-r", RNA_FA, "-k", "21"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_validate() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["validate", "-j", STRANDS_FA])
+        .assert()
+        .success()
+        .stdout(
+            "kmer_size\t21\njunctions\t2\nkmers\t2\n\
+            rejected\t0\nduplicated\t0\n",
+        );
+
+    // A rejected junction fails the check
+    Command::cargo_bin(PRG)?
+        .args(["validate", "-j", KMER21_FA, "-k", "21"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("rejected\t1\n"))
+        .stderr(predicate::str::contains("1 of 4 junctions rejected"));
+
+    // So does an index that is not one
+    Command::cargo_bin(PRG)?
+        .args(["validate", "--index", STRANDS_FA])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not a tallyman index"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_merge_stats() -> Result<()> {
+    // Count the same reads twice, as if they were two lanes
+    let outdir = TempDir::new()?;
+    let outdir_name = outdir.path().to_string_lossy().to_string();
+    let lane = outdir.path().join("lane2.fa");
+    fs::copy(RNA_FA, &lane)?;
+    let lane = lane.to_string_lossy().to_string();
+    Command::cargo_bin(PRG)?
+        .args(["count", "-j", MISMATCH_FA, "-m", "1", "-s", "both"])
+        .args(["-r", RNA_FA, &lane, "-o", &outdir_name])
+        .assert()
+        .success();
+
+    let first = outdir.path().join("rna.fa.txt");
+    let second = outdir.path().join("lane2.fa.txt");
+    let output = Command::cargo_bin(PRG)?
+        .arg("merge")
+        .args([&first, &second])
+        .output()?;
+    assert!(output.status.success());
+
+    // Every count is doubled
+    let single = fs::read_to_string(&first)?;
+    let merged = String::from_utf8(output.stdout)?;
+    let mut merged_lines = merged.lines();
+    assert_eq!(
+        merged_lines.next(),
+        Some(format!("File: {RNA_FA},{lane}").as_str())
+    );
+    for (single, merged) in single.lines().skip(1).zip(merged_lines) {
+        let single: Vec<_> = single.split('\t').collect();
+        let merged: Vec<_> = merged.split('\t').collect();
+        assert_eq!(single[0], merged[0]);
+        for (single, merged) in single[1..].iter().zip(&merged[1..]) {
+            assert_eq!(2 * single.parse::<u64>()?, merged.parse::<u64>()?);
+        }
+    }
+
+    // Counts with different columns don't add up
+    let other = outdir.path().join("forward.txt");
+    fs::write(&other, "File: x\npolyC\t24\n")?;
+    Command::cargo_bin(PRG)?
+        .arg("merge")
+        .args([&first, &other])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("has 1 counts, expected 6"));
+
+    let rows = single.lines().count() - 1;
+    Command::cargo_bin(PRG)?
+        .args(["stats", &outdir_name])
+        .assert()
+        .success()
+        .stdout(format!(
+            "file\treads\tshort_reads\tjunctions\n\
+            lane2.fa\t3\t0\t{rows}\nrna.fa\t3\t0\t{rows}\n"
+        ));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_merge_formats() -> Result<()> {
+    // Two lanes merge into just what counting them together writes
+    let outdir = TempDir::new()?;
+    let outdir_name = outdir.path().to_string_lossy().to_string();
+    let lane = outdir.path().join("lane2.fa");
+    fs::copy(RNA_FA, &lane)?;
+    let both = outdir.path().join("both.fa");
+    fs::write(&both, fs::read_to_string(RNA_FA)?.repeat(2))?;
+    let lanes = [RNA_FA, &lane.to_string_lossy()].map(String::from);
+
+    for format in ["tsv", "csv", "jsonl"] {
+        Command::cargo_bin(PRG)?
+            .args(["count", "-j", MISMATCH_FA, "-m", "1", "-s", "both"])
+            .args(["-r", &lanes[0], &lanes[1]])
+            .arg(&both)
+            .args(["-o", &outdir_name, "--format", format, "--report-zeros"])
+            .assert()
+            .success();
+
+        let merged = outdir.path().join(format!("merged.{format}"));
+        Command::cargo_bin(PRG)?
+            .arg("merge")
+            .arg(outdir.path().join(format!("rna.fa.{format}")))
+            .arg(outdir.path().join(format!("lane2.fa.{format}")))
+            .arg("-o")
+            .arg(&merged)
+            .assert()
+            .success();
+
+        let expected = fs::read_to_string(
+            outdir.path().join(format!("both.fa.{format}")),
+        )?;
+        let sample = match format {
+            "csv" => r#""rna.fa,lane2.fa""#,
+            _ => "rna.fa,lane2.fa",
+        };
+        assert_eq!(
+            fs::read_to_string(&merged)?,
+            expected.replace("both.fa", sample),
+            "{format}"
+        );
+    }
+
+    // Only counts of one format add up
+    Command::cargo_bin(PRG)?
+        .arg("merge")
+        .arg(outdir.path().join("rna.fa.tsv"))
+        .arg(outdir.path().join("lane2.fa.csv"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "written in another format than the first",
+        ));
+
+    #[cfg(feature = "columnar")]
+    {
+        Command::cargo_bin(PRG)?
+            .args(["count", "-j", MISMATCH_FA, "-r", RNA_FA])
+            .args(["-o", &outdir_name, "--format", "parquet"])
+            .assert()
+            .success();
+        Command::cargo_bin(PRG)?
+            .arg("merge")
+            .arg(outdir.path().join("rna.fa.parquet"))
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "only counts in a text format can be merged",
+            ));
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_formats() -> Result<()> {
+    let header = "sample\tjunction\tsequence\tforward\treverse\ttotal\t\
+        mismatch_forward\tmismatch_reverse\tmismatch_total\treads";
+    let rows = [
+        "rna.fa\tpolyC\tCCCCCCCCCCCCCCCCCCCCC\t24\t0\t24\t0\t0\t0\t3",
+        "rna.fa\tacgt\tACGTACGTACGTACGTACGTA\t6\t6\t12\t0\t0\t0\t3",
+        "rna.fa\tabsent\tGGGGGGGGGGGGGGGGGGGGG\t0\t24\t24\t0\t0\t0\t3",
+    ];
+
+    for format in ["tsv", "csv", "jsonl"] {
+        let outdir = TempDir::new()?;
+        let outdir_name = outdir.path().to_string_lossy().to_string();
+        Command::cargo_bin(PRG)?
+            .args([
+                "count", "-j", KMER21_FA, "-k", "21", "-s", "both", "-r",
+                RNA_FA,
+            ])
+            .args(["-o", &outdir_name, "--format", format])
+            .assert()
+            .success();
+
+        let actual = fs::read_to_string(
+            outdir.path().join(format!("rna.fa.{format}")),
+        )?;
+        let fields: Vec<_> = header.split('\t').collect();
+        let expected: Vec<String> = match format {
+            "tsv" => [header]
+                .iter()
+                .chain(&rows)
+                .map(|row| row.to_string())
+                .collect(),
+            "csv" => [header]
+                .iter()
+                .chain(&rows)
+                .map(|row| row.replace('\t', ","))
+                .collect(),
+            _ => rows
+                .iter()
+                .map(|row| {
+                    let values = row.split('\t').enumerate().map(
+                        |(i, value)| match i {
+                            0..=2 => format!(r#""{}":"{value}""#, fields[i]),
+                            _ => format!(r#""{}":{value}"#, fields[i]),
+                        },
+                    );
+                    format!("{{{}}}", values.collect::<Vec<_>>().join(","))
+                })
+                .collect(),
+        };
+
+        let actual: Vec<_> = actual.lines().collect();
+        assert_eq!(actual, expected, "{format}");
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_sort() -> Result<()> {
+    // Ties on the hits keep the order of the junctions file
+    for (sort, expected) in [
+        (
+            None,
+            ["polyC\t24\t0\t24", "acgt\t6\t6\t12", "absent\t0\t24\t24"],
+        ),
+        (
+            Some("input"),
+            ["polyC\t24\t0\t24", "acgt\t6\t6\t12", "absent\t0\t24\t24"],
+        ),
+        (
+            Some("name"),
+            ["absent\t0\t24\t24", "acgt\t6\t6\t12", "polyC\t24\t0\t24"],
+        ),
+        (
+            Some("count"),
+            ["polyC\t24\t0\t24", "absent\t0\t24\t24", "acgt\t6\t6\t12"],
+        ),
+    ] {
+        let outdir = TempDir::new()?;
+        let outdir_name = outdir.path().to_string_lossy().to_string();
+        let matrix = outdir.path().join("matrix.tsv");
+        let mut cmd = Command::cargo_bin(PRG)?;
+        cmd.args(["count", "-j", KMER21_FA, "-k", "21", "-s", "both"])
+            .args(["-r", RNA_FA, STRANDS_FA, "-o", &outdir_name])
+            .arg("--matrix")
+            .arg(&matrix);
+        if let Some(sort) = sort {
+            cmd.args(["--sort", sort]);
+        }
+        cmd.assert().success();
+
+        let actual = fs::read_to_string(outdir.path().join("rna.fa.txt"))?;
+        let actual: Vec<_> = actual.lines().skip(1).collect();
+        assert_eq!(actual, expected, "{sort:?}");
+
+        // The matrix follows the same order, by the hits in every file
+        let matrix = fs::read_to_string(&matrix)?;
+        let rows: Vec<_> = matrix
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(name, _)| name)
+            .collect();
+        let names: Vec<_> = expected
+            .iter()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(name, _)| name)
+            .chain(["total_reads"])
+            .collect();
+        assert_eq!(rows, names, "{sort:?}");
+    }
+
+    Command::cargo_bin(PRG)?
+        .args(["count", "-j", KMER21_FA, "-r", RNA_FA, "-o", "out"])
+        .args(["--sort", "slot"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid value 'slot'"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_report_zeros() -> Result<()> {
+    for (opts, expected) in [
+        (&[][..], ["polyC\t1\t1\t2", "absent\t1\t1\t2"].as_slice()),
+        (
+            &["--report-zeros"],
+            &["polyC\t1\t1\t2", "acgt\t0\t0\t0", "absent\t1\t1\t2"],
+        ),
+        (
+            &["--min-count", "2"],
+            &["polyC\t1\t1\t2", "absent\t1\t1\t2"],
+        ),
+        (&["--min-count", "3"], &[]),
+    ] {
+        let (lines, _) = run_small(
+            STRANDS_FA,
+            KMER21_FA,
+            &[opts, &["-k", "21", "-s", "both"]].concat(),
+        )?;
+        let mut expected = expected.to_vec();
+        expected.sort();
+        assert_eq!(lines, expected, "{opts:?}");
+    }
+
+    // Every format writes the same junctions, and the stats count those
+    // with hits, not the rows of zeros
+    let formats = ["legacy", "tsv", "csv", "jsonl"];
+    #[cfg(feature = "columnar")]
+    let formats = [&formats[..], &["parquet", "arrow"]].concat();
+    for format in formats {
+        for (opts, rna, strands) in [
+            (&["--report-zeros"][..], 3, 2),
+            (&["--min-count", "2"], 3, 2),
+            (&["--min-count", "20"], 2, 0),
+            (&["--min-count", "25"], 0, 0),
+        ] {
+            let outdir = TempDir::new()?;
+            let outdir_name = outdir.path().to_string_lossy().to_string();
+            Command::cargo_bin(PRG)?
+                .args(["count", "-j", KMER21_FA, "-k", "21", "-s", "both"])
+                .args(["-r", RNA_FA, STRANDS_FA, "-o", &outdir_name])
+                .args(["--format", format])
+                .args(opts)
+                .assert()
+                .success();
+            Command::cargo_bin(PRG)?
+                .args(["stats", &outdir_name])
+                .assert()
+                .success()
+                .stdout(format!(
+                    "file\treads\tshort_reads\tjunctions\n\
+                    rna.fa\t3\t0\t{rna}\nstrands.fa\t2\t0\t{strands}\n"
+                ));
+        }
+    }
+
+    Command::cargo_bin(PRG)?
+        .args(["count", "-j", KMER21_FA, "-r", RNA_FA, "-o", "out"])
+        .args(["--report-zeros", "--min-count", "2"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+    Ok(())
+}
+
+// --------------------------------------------------
+/// The rows of a Parquet or Arrow table, tab-separated.
+#[cfg(feature = "columnar")]
+fn read_columnar(
+    path: &Path,
+    format: tallyman::columnar::Columnar,
+) -> Result<Vec<String>> {
+    use arrow::util::display::array_value_to_string;
+
+    let mut rows = vec![];
+    for batch in tallyman::columnar::read_table(path, format)? {
+        for row in 0..batch.num_rows() {
+            let values = batch
+                .columns()
+                .iter()
+                .map(|column| array_value_to_string(column, row))
+                .collect::<Result<Vec<_>, _>>()?;
+            rows.push(values.join("\t"));
+        }
+    }
+    Ok(rows)
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(feature = "columnar")]
+fn run_columnar() -> Result<()> {
+    use tallyman::columnar::Columnar;
+
+    let rows = [
+        "rna.fa\tpolyC\tCCCCCCCCCCCCCCCCCCCCC\t24\t0\t24\t0\t0\t0\t3",
+        "rna.fa\tacgt\tACGTACGTACGTACGTACGTA\t6\t6\t12\t0\t0\t0\t3",
+        "rna.fa\tabsent\tGGGGGGGGGGGGGGGGGGGGG\t0\t24\t24\t0\t0\t0\t3",
+    ];
+    let matrix_rows = [
+        "rna.fa\tpolyC\tCCCCCCCCCCCCCCCCCCCCC\t24\t0\t24\t0\t0\t0\t3",
+        "rna.fa\tacgt\tACGTACGTACGTACGTACGTA\t6\t6\t12\t0\t0\t0\t3",
+        "rna.fa\tabsent\tGGGGGGGGGGGGGGGGGGGGG\t0\t24\t24\t0\t0\t0\t3",
+        "strands.fa\tpolyC\tCCCCCCCCCCCCCCCCCCCCC\t1\t1\t2\t0\t0\t0\t2",
+        "strands.fa\tacgt\tACGTACGTACGTACGTACGTA\t0\t0\t0\t0\t0\t0\t2",
+        "strands.fa\tabsent\tGGGGGGGGGGGGGGGGGGGGG\t1\t1\t2\t0\t0\t0\t2",
+    ];
+
+    for (name, format) in
+        [("parquet", Columnar::Parquet), ("arrow", Columnar::Arrow)]
+    {
+        let outdir = TempDir::new()?;
+        let outdir_name = outdir.path().to_string_lossy().to_string();
+        let matrix = outdir.path().join(format!("matrix.{name}"));
+        Command::cargo_bin(PRG)?
+            .args(["count", "-j", KMER21_FA, "-k", "21", "-s", "both"])
+            .args(["-r", RNA_FA, STRANDS_FA, "-o", &outdir_name])
+            .args(["--format", name, "--matrix-format", name, "--matrix"])
+            .arg(&matrix)
+            .assert()
+            .success();
+
+        let actual = read_columnar(
+            &outdir.path().join(format!("rna.fa.{name}")),
+            format,
+        )?;
+        assert_eq!(actual, rows, "{name}");
+
+        assert_eq!(read_columnar(&matrix, format)?, matrix_rows, "{name}");
+
+        // The stats find the hits in whichever format they were written
+        Command::cargo_bin(PRG)?
+            .args(["stats", &outdir_name])
+            .assert()
+            .success()
+            .stdout(
+                "file\treads\tshort_reads\tjunctions\n\
+                rna.fa\t3\t0\t3\n\
+                strands.fa\t2\t0\t2\n",
+            );
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_matrix() -> Result<()> {
+    let outdir = TempDir::new()?;
+    let outdir_name = outdir.path().to_string_lossy().to_string();
+    let matrix = outdir.path().join("matrix.tsv");
+    Command::cargo_bin(PRG)?
+        .args(["count", "-j", KMER21_FA, "-k", "21", "-s", "both"])
+        .args(["-r", RNA_FA, STRANDS_FA, "-o", &outdir_name])
+        .arg("--matrix")
+        .arg(&matrix)
+        .assert()
+        .success();
+
+    // Every junction kept, in file order, whether it was hit or not
+    assert_eq!(
+        fs::read_to_string(&matrix)?,
+        "junction\trna.fa\tstrands.fa\n\
+        polyC\t24\t2\n\
+        acgt\t12\t0\n\
+        absent\t24\t2\n\
+        total_reads\t3\t2\n"
+    );
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_threads() -> Result<()> {
+    // Splitting a file across threads counts just what one thread does,
+    // whether the threads keep their own counts or share atomic ones
+    for (read_file, junction_file, opts) in [
+        (RNA_FA, KMER21_FA, &[][..]),
+        (RNA_FA, MISMATCH_FA, &["-m", "1", "-s", "both"]),
+        (RNA_FA_50K, DNA_FA, &["--prefilter"]),
+        (
+            RNA_FA_50K,
+            DNA_FA,
+            &["--backend", "perfect", "-s", "canonical"],
+        ),
+        (RNA_FA_50K, DNA_FA, &["--shared-counts", "-s", "both"]),
+        (
+            RNA_FA,
+            MISMATCH_FA,
+            &["--shared-counts", "-m", "2", "--wide-counts"],
+        ),
+    ] {
+        let serial = [opts, &["--threads", "1"]].concat();
+        let parallel = [opts, &["--threads", "4"]].concat();
+        assert_eq!(
+            run_small(read_file, junction_file, &parallel)?,
+            run_small(read_file, junction_file, &serial)?
+        );
+        assert_eq!(
+            read_stats(read_file, junction_file, &parallel)?,
+            read_stats(read_file, junction_file, &serial)?
+        );
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn run_prefilter() -> Result<()> {
+    // The prefilter never changes what is counted
+    for (read_file, junction_file, opts) in [
+        (RNA_FA, KMER21_FA, &[][..]),
+        (RNA_FA, STRANDS_FA, &["-s", "both"]),
+        (RNA_FA, MISMATCH_FA, &["-m", "1", "--backend", "perfect"]),
+        (RNA_FA_50K, DNA_FA, &["--prefilter-fp-rate", "0.5"]),
+    ] {
+        let (expected, _) = run_small(read_file, junction_file, opts)?;
+        let opts = [opts, &["--prefilter"]].concat();
+        let (actual, _) = run_small(read_file, junction_file, &opts)?;
+        assert_eq!(actual, expected);
+    }
+
+    // Only then are its lookups and rejections reported
+    let stats = read_stats(RNA_FA_50K, DNA_FA, &["--prefilter"])?;
+    let stats: Vec<_> = stats
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .collect();
+    let names: Vec<_> = stats.iter().map(|(name, _)| *name).collect();
+    assert_eq!(
+        names,
+        [
+            "reads",
+            "short_reads",
+            "prefilter_lookups",
+            "prefilter_rejected"
+        ]
+    );
+    let lookups: u64 = stats[2].1.parse()?;
+    let rejected: u64 = stats[3].1.parse()?;
+    assert!(rejected > 0);
+    assert!(rejected <= lookups);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_prefilter_fp_rate() -> Result<()> {
+    for bad in ["0", "1", "1.5", "foo"] {
+        let expected =
+            format!(r#"false positive rate "{bad}" must be between 0 and 1"#);
+        Command::cargo_bin(PRG)?
+            .args([
+                "count",
+                "-j",
+                DNA_FA,
+                "-r",
+                RNA_FA,
+                "--prefilter-fp-rate",
+                bad,
+            ])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(expected));
+    }
+    Ok(())
+}